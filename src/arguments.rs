@@ -45,7 +45,51 @@ pub enum Command {
         /// Automatically download and distribute songs from other distributors
         #[arg(long, value_enum)]
         demo: Option<Demo>,
+
+        /// Detach from the terminal and keep running in the background
+        #[arg(long)]
+        daemon: bool,
+    },
+
+    /// Control a running distributor through its control-socket
+    #[command(subcommand)]
+    Ctl(CtlCommand),
+
+    /// Report the earnings from distributing
+    Earnings {
+        /// How to group the earnings
+        #[arg(long, value_enum, default_value_t = EarningsBy::Song)]
+        by: EarningsBy,
     },
+
+    /// Report the spending on downloads from distributors
+    Spending {
+        /// How to group the spending
+        #[arg(long, value_enum, default_value_t = SpendingBy::Song)]
+        by: SpendingBy,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum EarningsBy {
+    /// The total per song
+    Song,
+    /// The total per listener
+    Listener,
+    /// The total per day
+    Day,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum SpendingBy {
+    /// The total per song
+    Song,
+    /// The total per distributor
+    Distributor,
+    /// The total per day
+    Day,
+    /// The total per status of the transactions
+    Status,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
@@ -115,6 +159,25 @@ pub enum WalletCommand {
     RequestFunds,
 }
 
+#[derive(clap::Subcommand, Debug, Clone, Serialize, Deserialize)]
+pub enum CtlCommand {
+    /// Show the status of the running distributor
+    Status,
+
+    /// Manage the songs of the running distributor
+    #[command(subcommand)]
+    Songs(CtlSongsCommand),
+
+    /// Stop the running distributor gracefully
+    Stop,
+}
+
+#[derive(clap::Subcommand, Debug, Clone, Serialize, Deserialize)]
+pub enum CtlSongsCommand {
+    /// Add songs from mp3-files named `<SONG_ID>.mp3`, which are registered soon after
+    Add { paths: Vec<String> },
+}
+
 #[derive(clap::Subcommand, Debug, Clone, Serialize, Deserialize)]
 pub enum AccountCommand {
     /// Deposit into your account from your wallet
@@ -177,7 +240,7 @@ pub enum SongIndexCommand {
 pub enum SongsCommand {
     /// Download chunks from a distributor's ip-address
     DownloadDirect {
-        /// The ip-address of the distributor
+        /// The ip-address of the distributor, prefixed with `tls://` for TLS
         #[arg(long)]
         ip: String,
 
@@ -228,6 +291,9 @@ pub enum SongsCommand {
         ids: Vec<String>,
     },
 
-    /// List all songs in the database
+    /// List all songs in storage
     List,
+
+    /// Check the chunks of all stored songs against their hashes
+    Verify,
 }
@@ -0,0 +1,109 @@
+use crate::arguments::{CtlCommand, CtlSongsCommand};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+
+/// A request to a running distributor over its control-socket, sent as a single line of json.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    Status,
+    AddSongs { paths: Vec<String> },
+    Stop,
+}
+
+/// The response of a running distributor to a [`ControlRequest`], sent as a single line of json.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ControlResponse {
+    Status(DistributorStatus),
+    Ok,
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DistributorStatus {
+    pub pid: u32,
+    pub server_address: String,
+    /// Our fee per chunk in IOTA.
+    pub fee: u32,
+    pub songs: usize,
+    pub connections: usize,
+    /// The chunks sent to listeners that have not been paid for yet.
+    pub outstanding_credit: i64,
+}
+
+/// Run the ctl-command against the distributor that listens on the control-socket.
+pub async fn run(control_socket: &Path, command: CtlCommand) -> eyre::Result<()> {
+    let request = match command {
+        CtlCommand::Status => ControlRequest::Status,
+        CtlCommand::Songs(CtlSongsCommand::Add { paths }) => {
+            // The distributor may run in another directory, so the paths are made absolute.
+            let paths = paths
+                .iter()
+                .map(|path| Ok(std::fs::canonicalize(path)?.to_string_lossy().into_owned()))
+                .collect::<eyre::Result<_>>()?;
+            ControlRequest::AddSongs { paths }
+        }
+        CtlCommand::Stop => ControlRequest::Stop,
+    };
+
+    match send(control_socket, &request).await? {
+        ControlResponse::Status(status) => {
+            println!("-- Distributor (pid {}) --", status.pid);
+            println!("| server address: {}", status.server_address);
+            println!("| fee: {} IOTA per chunk", status.fee);
+            println!("| songs: {}", status.songs);
+            println!("| open connections: {}", status.connections);
+            println!("| outstanding credit: {} chunks", status.outstanding_credit);
+        }
+        ControlResponse::Ok => match request {
+            ControlRequest::Stop => println!("Distributor is stopping"),
+            _ => println!("Done"),
+        },
+        ControlResponse::Error(e) => bail!("Distributor refused the request: {e}"),
+    }
+    Ok(())
+}
+
+/// Send a single request over the control-socket and wait for its response.
+async fn send(control_socket: &Path, request: &ControlRequest) -> eyre::Result<ControlResponse> {
+    let stream = UnixStream::connect(control_socket).await.map_err(|e| {
+        eyre!(
+            "No distributor is running with control-socket {}: {e}",
+            control_socket.display()
+        )
+    })?;
+    let (read_half, mut write_half) = stream.into_split();
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    write_half.write_all(line.as_bytes()).await?;
+
+    let mut response = String::new();
+    BufReader::new(read_half).read_line(&mut response).await?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_are_single_json_lines() -> eyre::Result<()> {
+        let request = ControlRequest::AddSongs {
+            paths: vec!["/songs/a.mp3".into()],
+        };
+        let line = serde_json::to_string(&request)?;
+        assert_eq!(line, r#"{"command":"add-songs","paths":["/songs/a.mp3"]}"#);
+        assert_eq!(serde_json::from_str::<ControlRequest>(&line)?, request);
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(r#"{"command":"stop"}"#)?,
+            ControlRequest::Stop
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+use crate::library::{
+    client::{GetChunksTx, InvalidTxError, TangleTunesClient},
+    tcp::ErrorCode,
+    util::SongId,
+};
+use ethers::types::{Address, H256, U256};
+use ethers_providers::{JsonRpcError, RpcError};
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// How long a looked up account-balance is trusted before it is fetched again.
+const ACCOUNT_TTL: Duration = Duration::from_secs(30);
+
+/// The reason a listener is refused before any chunks are sent.
+#[derive(Debug, thiserror::Error)]
+pub enum AccountError {
+    #[error("Listener {0:?} does not have a TangleTunes account")]
+    NoAccount(Address),
+    #[error("Song {0} does not exist on the smart-contract")]
+    UnknownSong(SongId),
+    #[error("Listener {listener:?} cannot pay for the chunks: (balance = {balance}, required = {required})")]
+    InsufficientBalance {
+        listener: Address,
+        balance: U256,
+        required: U256,
+    },
+    #[error(transparent)]
+    InvalidTx(#[from] InvalidTxError),
+    #[error("Could not look up the account: {0:#}")]
+    Lookup(#[from] eyre::Report),
+}
+
+impl AccountError {
+    /// The error-code with which the listener is refused.
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            AccountError::NoAccount(_)
+            | AccountError::InsufficientBalance { .. }
+            | AccountError::InvalidTx(InvalidTxError::InsufficientGasFunds { .. }) => {
+                ErrorCode::InsufficientFunds
+            }
+            AccountError::UnknownSong(_) | AccountError::InvalidTx(_) => ErrorCode::BadTransaction,
+            AccountError::Lookup(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+/// Whether the error-response of the node means that the transaction reverted, rather than that
+/// the node could not execute it.
+fn is_revert(response: &JsonRpcError) -> bool {
+    const EXECUTION_REVERTED: i64 = 3;
+    response.code == EXECUTION_REVERTED || response.message.starts_with("execution reverted")
+}
+
+/// The on-chain balances of a listener, and the transactions that have been committed to them
+/// since they were looked up.
+struct CachedAccount {
+    fetched_at: Instant,
+    /// The balance on the smart-contract.
+    balance: U256,
+    /// The balance of the wallet used for gas.
+    gas_balance: U256,
+    commitments: HashMap<H256, Commitment>,
+}
+
+/// The balance and gas a transaction of the listener can spend at most.
+struct Commitment {
+    amount: U256,
+    gas: U256,
+    /// Whether the transaction has been settled. Its cost stays committed until the balances are
+    /// looked up again, since they do not include it yet.
+    settled: bool,
+}
+
+impl CachedAccount {
+    fn new(balance: U256, gas_balance: U256) -> Self {
+        Self {
+            fetched_at: Instant::now(),
+            balance,
+            gas_balance,
+            commitments: HashMap::new(),
+        }
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.fetched_at.elapsed() < ACCOUNT_TTL
+    }
+
+    /// Update the balances to the ones that were looked up again. The transactions that are
+    /// still pending stay committed, since they are not included in the balances yet.
+    fn refresh(&mut self, balance: U256, gas_balance: U256) {
+        self.fetched_at = Instant::now();
+        self.balance = balance;
+        self.gas_balance = gas_balance;
+        self.commitments.retain(|_, commitment| !commitment.settled);
+    }
+
+    fn committed(&self) -> U256 {
+        self.commitments
+            .values()
+            .fold(U256::zero(), |sum, commitment| {
+                sum.saturating_add(commitment.amount)
+            })
+    }
+
+    fn committed_gas(&self) -> U256 {
+        self.commitments
+            .values()
+            .fold(U256::zero(), |sum, commitment| {
+                sum.saturating_add(commitment.gas)
+            })
+    }
+}
+
+/// The price of a song and our fee for it, both per chunk and in wei.
+#[derive(Clone, Copy)]
+struct SongCost {
+    price: U256,
+    fee: U256,
+}
+
+impl SongCost {
+    fn for_chunks(&self, amount: U256) -> U256 {
+        amount.saturating_mul(self.price.saturating_add(self.fee))
+    }
+}
+
+/// A cache of the accounts of listeners and the songs they request, which lives as long as a
+/// single connection. This is used to check that a listener can pay for their requests without
+/// querying the node for every request.
+pub struct AccountCache {
+    client: &'static TangleTunesClient,
+    accounts: HashMap<Address, CachedAccount>,
+    songs: HashMap<SongId, SongCost>,
+}
+
+impl AccountCache {
+    pub fn new(client: &'static TangleTunesClient) -> Self {
+        Self {
+            client,
+            accounts: HashMap::new(),
+            songs: HashMap::new(),
+        }
+    }
+
+    /// Checks that the sender of the transaction has an account with enough balance to pay for
+    /// the song-price, our fee and the gas, and that the transaction would not revert. If so, the
+    /// cost is committed to the cached balance.
+    pub async fn check(&mut self, tx: &GetChunksTx) -> Result<(), AccountError> {
+        let client = self.client;
+        let song_id = SongId::from(tx.params.song);
+        let Some(cost) = self.song_cost(song_id).await? else {
+            return Err(AccountError::UnknownSong(song_id));
+        };
+
+        let Some(account) = self.account(tx.sender).await? else {
+            return Err(AccountError::NoAccount(tx.sender));
+        };
+
+        let amount = cost.for_chunks(tx.params.amount);
+        let required = account.committed().saturating_add(amount);
+        if account.balance < required {
+            return Err(AccountError::InsufficientBalance {
+                listener: tx.sender,
+                balance: account.balance,
+                required,
+            });
+        }
+
+        let gas = tx.max_gas_cost();
+        let required_gas = account.committed_gas().saturating_add(gas);
+        if account.gas_balance < required_gas {
+            return Err(InvalidTxError::InsufficientGasFunds {
+                sender: tx.sender,
+                balance: account.gas_balance,
+                required: required_gas,
+            }
+            .into());
+        }
+
+        // Dry-run the transaction, so that transactions that would revert are refused before any
+        // credit is given for them.
+        match client.simulate_get_chunks_tx(tx).await {
+            Ok(()) => (),
+            Err(e) => match e.as_error_response() {
+                Some(response) if is_revert(response) => {
+                    return Err(InvalidTxError::WouldRevert(response.message.clone()).into())
+                }
+                _ => return Err(AccountError::Lookup(e.into())),
+            },
+        }
+
+        account.commitments.insert(
+            tx.hash,
+            Commitment {
+                amount,
+                gas,
+                settled: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Mark the transaction of the listener as settled, after which its cost is no longer
+    /// committed once the balances are looked up again.
+    pub fn settle(&mut self, listener: Address, tx_hash: H256) {
+        let commitment = self
+            .accounts
+            .get_mut(&listener)
+            .and_then(|account| account.commitments.get_mut(&tx_hash));
+        if let Some(commitment) = commitment {
+            commitment.settled = true;
+        }
+    }
+
+    /// Our fee for the chunks of a song that has been checked before, in wei.
+    pub fn fee_for_chunks(&self, song_id: SongId, amount: U256) -> U256 {
+        self.songs
+            .get(&song_id)
+            .map(|cost| cost.fee.saturating_mul(amount))
+            .unwrap_or_default()
+    }
+
+    /// Get the cost of the song, or `None` if it does not exist.
+    async fn song_cost(&mut self, song_id: SongId) -> eyre::Result<Option<SongCost>> {
+        if let Some(cost) = self.songs.get(&song_id) {
+            return Ok(Some(*cost));
+        }
+
+        let song_info = self
+            .client
+            .get_song_info(song_id)
+            .await
+            .map_err(eyre::Report::from)?;
+        if !song_info.exists {
+            return Ok(None);
+        }
+        let fee = self
+            .client
+            .get_distribution_fee(song_id, self.client.wallet_address())
+            .await
+            .map_err(eyre::Report::from)?;
+
+        let cost = SongCost {
+            price: song_info.price,
+            fee,
+        };
+        self.songs.insert(song_id, cost);
+        Ok(Some(cost))
+    }
+
+    /// Get the cached account of the listener, or `None` if they do not have an account. The
+    /// account is fetched again if the cached version is too old.
+    async fn account(&mut self, listener: Address) -> eyre::Result<Option<&mut CachedAccount>> {
+        let is_fresh = self
+            .accounts
+            .get(&listener)
+            .map(CachedAccount::is_fresh)
+            .unwrap_or(false);
+
+        if !is_fresh {
+            let user_info = self
+                .client
+                .get_user_info(listener)
+                .await
+                .map_err(eyre::Report::from)?;
+            if !user_info.exists {
+                self.accounts.remove(&listener);
+                return Ok(None);
+            }
+            let gas_balance = self.client.l2_balance_of(listener).await?;
+
+            match self.accounts.get_mut(&listener) {
+                Some(account) => account.refresh(user_info.balance, gas_balance),
+                None => {
+                    let account = CachedAccount::new(user_info.balance, gas_balance);
+                    self.accounts.insert(listener, account);
+                }
+            }
+        }
+
+        Ok(self.accounts.get_mut(&listener))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn song_cost_is_per_chunk() {
+        let cost = SongCost {
+            price: 3.into(),
+            fee: 7.into(),
+        };
+        assert_eq!(cost.for_chunks(0.into()), 0.into());
+        assert_eq!(cost.for_chunks(10.into()), 100.into());
+    }
+
+    #[test]
+    fn only_reverts_are_reverts() {
+        let response = |code, message: &str| JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        };
+        assert!(is_revert(&response(
+            3,
+            "execution reverted: Song does not exist"
+        )));
+        assert!(is_revert(&response(-32000, "execution reverted")));
+        assert!(!is_revert(&response(-32000, "header not found")));
+        assert!(!is_revert(&response(-32005, "rate limit exceeded")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_transactions_stay_committed_after_refresh() {
+        let commitment = |amount: u32| Commitment {
+            amount: amount.into(),
+            gas: 1.into(),
+            settled: false,
+        };
+        let mut account = CachedAccount::new(100.into(), 10.into());
+        account
+            .commitments
+            .insert(H256::from_low_u64_be(1), commitment(30));
+        account
+            .commitments
+            .insert(H256::from_low_u64_be(2), commitment(40));
+        account
+            .commitments
+            .get_mut(&H256::from_low_u64_be(1))
+            .unwrap()
+            .settled = true;
+        assert_eq!(account.committed(), 70.into());
+
+        // After the TTL the balances are looked up again, which include the settled payment but
+        // not the pending one.
+        tokio::time::advance(ACCOUNT_TTL).await;
+        assert!(!account.is_fresh());
+        account.refresh(70.into(), 9.into());
+        assert!(account.is_fresh());
+        assert_eq!(account.committed(), 40.into());
+        assert_eq!(account.committed_gas(), 1.into());
+    }
+}
@@ -0,0 +1,230 @@
+use super::{distribution::update_fee_of_songs_in_database, SharedState, DISTR_SIZE};
+use crate::{
+    command,
+    library::{
+        app::{AdminSettings, App},
+        util::SongId,
+    },
+};
+use hyper::{
+    body,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+
+/// An error of the admin-api, which is returned to the client as json.
+#[derive(Debug, thiserror::Error)]
+enum ApiError {
+    #[error("Missing or invalid bearer-token")]
+    Unauthorized,
+    #[error("Not found")]
+    NotFound,
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0:#}")]
+    Internal(#[from] eyre::Report),
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AddSong {
+    /// The path of the mp3-file, named `<SONG_ID>.mp3`.
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct SetFee {
+    /// The new fee per chunk in IOTA.
+    fee: u32,
+}
+
+/// Serve the admin-api on the address of the settings. Every request must carry the token of the
+/// settings as bearer-token.
+///
+/// - `GET /songs`: The songs in the database.
+/// - `POST /songs` with `{"path": "<SONG_ID>.mp3"}`: Add a song, which is registered soon after.
+/// - `DELETE /songs/<SONG_ID>`: Deregister and remove a song.
+/// - `GET /registration`: Our server-address and which songs we are registered for.
+/// - `PUT /fee` with `{"fee": <IOTA>}`: Change our fee and update it for all songs.
+/// - `GET /connections`: The open connections of listeners.
+/// - `POST /shutdown`: Stop distributing gracefully.
+pub async fn serve(
+    app: &'static App,
+    shared: &'static SharedState,
+    settings: &'static AdminSettings,
+) -> eyre::Result<()> {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request| async move {
+            let response = match authorize(&request, &settings.token) {
+                Ok(()) => route(app, shared, request).await,
+                Err(e) => Err(e),
+            };
+            Ok::<_, Infallible>(match response {
+                Ok(value) => to_response(StatusCode::OK, value),
+                Err(e) => to_response(e.status(), json!({ "error": e.to_string() })),
+            })
+        }))
+    });
+
+    if !settings.address.ip().is_loopback() {
+        eprintln!(
+            "WARNING: The admin-api is served on {}, which is not a loopback address. Anyone who \
+            can reach it can manage this distributor with the token, which is sent unencrypted.",
+            settings.address
+        );
+    }
+    println!("Serving admin-api on http://{}", settings.address);
+    Server::try_bind(&settings.address)?
+        .serve(make_service)
+        .await?;
+    Ok(())
+}
+
+fn authorize(request: &Request<Body>, token: &str) -> Result<(), ApiError> {
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(ApiError::Unauthorized)?;
+    if constant_time_eq(bearer.as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(ApiError::Unauthorized)
+    }
+}
+
+/// Compare the bytes without returning early, so the token cannot be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn route(
+    app: &'static App,
+    shared: &'static SharedState,
+    request: Request<Body>,
+) -> Result<Value, ApiError> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match (method, segments.as_slice()) {
+        (Method::GET, ["songs"]) => {
+            let mut songs = Vec::new();
+            for song_id in app.storage.list().await? {
+                let index = app.database.get_index_by_song_id(&song_id).await?;
+                let chunks = app.storage.chunk_count(&song_id).await?;
+                songs.push(json!({ "id": song_id.to_string(), "index": index, "chunks": chunks }));
+            }
+            Ok(json!({ "songs": songs }))
+        }
+        (Method::POST, ["songs"]) => {
+            let AddSong { path } = parse_body(request).await?;
+            command::songs::add(vec![path], app)
+                .await
+                .map_err(|e| ApiError::BadRequest(format!("{e:#}")))?;
+            Ok(json!({}))
+        }
+        (Method::DELETE, ["songs", song_id]) => {
+            let song_id = SongId::try_from_hex(song_id)
+                .map_err(|e| ApiError::BadRequest(format!("{e:#}")))?;
+            if app.storage.chunk_count(&song_id).await?.is_none() {
+                return Err(ApiError::NotFound);
+            }
+            app.client.try_undistribute(&vec![song_id]).await?;
+            app.storage.delete(&song_id).await?;
+            shared.chunk_cache.remove_song(&song_id);
+            println!("Removed song {song_id} through the admin-api");
+            Ok(json!({}))
+        }
+        (Method::GET, ["registration"]) => {
+            let song_ids = app.storage.list().await?;
+            let distributing = app
+                .client
+                .abi_client
+                .is_distributing(
+                    song_ids.iter().map(|id| (*id).into()).collect(),
+                    app.client.wallet_address(),
+                )
+                .await
+                .map_err(eyre::Report::from)?;
+            let songs: Vec<Value> = song_ids
+                .iter()
+                .zip(distributing)
+                .map(|(id, registered)| json!({ "id": id.to_string(), "registered": registered }))
+                .collect();
+            Ok(json!({
+                "server_address": app.server_address.to_string(),
+                "fee": app.fee().as_u32(),
+                "songs": songs,
+            }))
+        }
+        (Method::PUT, ["fee"]) => {
+            let SetFee { fee } = parse_body(request).await?;
+            app.set_fee(fee);
+            update_fee_of_songs_in_database(app, DISTR_SIZE).await?;
+            Ok(json!({ "fee": fee }))
+        }
+        (Method::GET, ["connections"]) => {
+            Ok(serde_json::to_value(shared.connections.snapshot()).map_err(eyre::Report::from)?)
+        }
+        (Method::POST, ["shutdown"]) => {
+            println!("Shutdown requested through the admin-api");
+            shared.shutdown.notify_one();
+            Ok(json!({}))
+        }
+        _ => Err(ApiError::NotFound),
+    }
+}
+
+async fn parse_body<T: DeserializeOwned>(request: Request<Body>) -> Result<T, ApiError> {
+    let bytes = body::to_bytes(request.into_body())
+        .await
+        .map_err(eyre::Report::from)?;
+    serde_json::from_slice(&bytes).map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
+fn to_response(status: StatusCode, value: Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(value.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri("/songs");
+        if let Some(authorization) = authorization {
+            builder = builder.header(AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn bearer_token_is_required() {
+        assert!(authorize(&request(Some("Bearer secret")), "secret").is_ok());
+        assert!(authorize(&request(Some("Bearer secre")), "secret").is_err());
+        assert!(authorize(&request(Some("Bearer secreT")), "secret").is_err());
+        assert!(authorize(&request(Some("secret")), "secret").is_err());
+        assert!(authorize(&request(None), "secret").is_err());
+    }
+}
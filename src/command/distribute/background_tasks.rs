@@ -1,32 +1,41 @@
 use crate::{
     arguments::Demo,
     command,
-    library::{app::App, util::SongId},
+    library::{app::App, metrics::METRICS, util::SongId},
 };
 use chrono::{DateTime, Utc};
 use ethers::types::U256;
 use rand::{distributions::Uniform, prelude::Distribution, thread_rng};
-use std::{
-    cmp::Reverse, collections::BinaryHeap, convert::Infallible, sync::Mutex, time::Duration,
-};
+use std::{cmp::Reverse, collections::BinaryHeap, convert::Infallible, time::Duration};
 use tokio::{
-    sync::oneshot,
+    signal::unix::{signal, Signal, SignalKind},
     time::{Instant, MissedTickBehavior},
 };
 
 const POLL_INTERVAL: Duration = Duration::from_secs(15);
 
-/// A future that returns when ctrl-c is pressed in the terminal.
+/// Listens for SIGINT (ctrl-c in the terminal) and SIGTERM.
 /// This can be used to gracefully exit by undistributing songs.
-pub fn exit_listener() -> eyre::Result<oneshot::Receiver<()>> {
-    static EXIT_SIGNAL: Mutex<Option<oneshot::Sender<()>>> = Mutex::new(None);
-
-    let (tx, rx) = oneshot::channel();
-    *EXIT_SIGNAL.lock().unwrap() = Some(tx);
-    ctrlc::set_handler(|| {
-        let _ = EXIT_SIGNAL.lock().unwrap().take().unwrap().send(());
-    })?;
-    Ok(rx)
+pub struct ExitListener {
+    interrupt: Signal,
+    terminate: Signal,
+}
+
+impl ExitListener {
+    /// Wait for the next exit-signal, and return its name.
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+}
+
+pub fn exit_listener() -> eyre::Result<ExitListener> {
+    Ok(ExitListener {
+        interrupt: signal(SignalKind::interrupt())?,
+        terminate: signal(SignalKind::terminate())?,
+    })
 }
 
 /// Automatically downloads new songs from the smart-contract, and watches for new songs added
@@ -43,13 +52,13 @@ pub async fn auto_distribute(app: &'static App, demo: Option<Demo>) -> Infallibl
     // Create the song-queue
     let mut queue = {
         let mut queue = NewSongQueue::new(Duration::from_secs(60));
-        let downloaded_ids = app.database.get_all_downloaded_song_ids().await.unwrap();
+        let downloaded_ids = app.storage.list().await.unwrap();
         app.database
             .get_song_index()
             .await
             .unwrap()
             .into_iter()
-            .filter(|(_, id)| (!downloaded_ids.contains(id)))
+            .filter(|(_, id)| !downloaded_ids.contains(id))
             .for_each(|(index, id)| queue.push(index, id));
         queue
     };
@@ -84,11 +93,11 @@ async fn download_a_new_song(
 
         // Take the front element from the queue
         let Some((index, id)) = queue.now() else {
-            return Ok(())
+            return Ok(());
         };
 
         // Check the demo-mode or if the song is already downloaded, and find the next one
-        if !demo.to_download(*index) || app.database.get_chunks(id, 0, 1).await.is_ok() {
+        if !demo.to_download(*index) || app.storage.size(id).await?.is_some() {
             queue.update(true);
             continue;
         };
@@ -122,10 +131,10 @@ async fn distribute_added_songs(
     let fee = if demo.is_some() {
         Uniform::new(200, 500).sample(&mut thread_rng()).into()
     } else {
-        app.fee
+        app.fee()
     };
 
-    for song_id in app.database.get_new_songs(from).await? {
+    for song_id in app.storage.list_since(from).await? {
         println!("Registering for song {song_id}...");
         if let Ok(pending_tx) = app
             .client
@@ -135,6 +144,7 @@ async fn distribute_added_songs(
             .await
         {
             if (pending_tx.await).is_ok() {
+                METRICS.set_songs_registered(&[song_id], true);
                 println!("Succesfully registered for song {song_id}.");
             } else {
                 println!("Registration for song {song_id} failed.");
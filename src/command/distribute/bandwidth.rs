@@ -0,0 +1,115 @@
+use std::time::Duration;
+use tokio::{sync::Mutex, time::Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token-bucket that limits the bytes sent per second, which can be shared across connections.
+///
+/// Bytes are taken from the bucket in pieces of at most `quantum` bytes. Waiters are served in
+/// order, so connections that share the bucket take turns per piece and all get steady throughput.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    /// The most tokens the bucket can hold, which is at least one quantum.
+    capacity: f64,
+    quantum: u64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    /// Create a full bucket that holds one second of bandwidth.
+    pub fn new(bytes_per_sec: u64, quantum: u64) -> Self {
+        assert!(bytes_per_sec > 0 && quantum > 0);
+        let capacity = Ord::max(bytes_per_sec, quantum) as f64;
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            capacity,
+            quantum,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until the bytes may be sent.
+    pub async fn acquire(&self, mut bytes: u64) {
+        while bytes > 0 {
+            let piece = Ord::min(bytes, self.quantum);
+            let mut bucket = self.bucket.lock().await;
+            self.refill(&mut bucket);
+            if bucket.tokens < piece as f64 {
+                let missing = piece as f64 - bucket.tokens;
+                tokio::time::sleep(Duration::from_secs_f64(missing / self.bytes_per_sec)).await;
+                self.refill(&mut bucket);
+            }
+            bucket.tokens -= piece as f64;
+            bytes -= piece;
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = f64::min(bucket.tokens + elapsed * self.bytes_per_sec, self.capacity);
+        bucket.last_refill = now;
+    }
+}
+
+/// Wait until the bytes may be sent under all of the rate-limiters.
+pub async fn acquire_all(limiters: &[Option<&RateLimiter>], bytes: u64) {
+    for limiter in limiters.iter().flatten() {
+        limiter.acquire(bytes).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn bandwidth_is_limited() {
+        let limiter = RateLimiter::new(1000, 100);
+        let start = Instant::now();
+
+        // The first second of bandwidth is available right away.
+        limiter.acquire(1000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        limiter.acquire(2000).await;
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((1.99..2.01).contains(&elapsed), "{elapsed}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_take_turns() {
+        let limiter = Arc::new(RateLimiter::new(1000, 100));
+        limiter.acquire(1000).await;
+        let start = Instant::now();
+
+        // A large and a small transfer share the bucket, and the small one is not starved.
+        let large = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(5000).await }
+        });
+        tokio::task::yield_now().await;
+        let small = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter.acquire(300).await;
+                start.elapsed()
+            }
+        });
+
+        let small_elapsed = small.await.unwrap().as_secs_f64();
+        assert!(small_elapsed < 1.0, "{small_elapsed}");
+        large.await.unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((5.29..5.31).contains(&elapsed), "{elapsed}");
+    }
+}
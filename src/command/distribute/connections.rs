@@ -0,0 +1,151 @@
+use crate::library::limits::ConnectionLimits;
+use ethers::types::Address;
+use serde::Serialize;
+use std::{collections::HashMap, hash::Hash, net::IpAddr, sync::Mutex};
+
+/// Why a connection is over the limits.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum LimitError {
+    #[error("The distributor has {0} open connections")]
+    Total(usize),
+    #[error("Address {0} has {1} open connections")]
+    Ip(IpAddr, usize),
+    #[error("Wallet {0:?} is used by {1} open connections")]
+    Wallet(Address, usize),
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct OpenConnections {
+    pub total: usize,
+    pub per_ip: HashMap<IpAddr, usize>,
+    pub per_wallet: HashMap<Address, usize>,
+}
+
+/// The open connections of listeners, shared across connections, to limit how many are open in
+/// total, per ip-address and per wallet-address.
+///
+/// Every open connection holds a [`ConnectionGuard`], which releases its slots when dropped.
+#[derive(Debug)]
+pub struct ConnectionTracker {
+    limits: ConnectionLimits,
+    open: Mutex<OpenConnections>,
+}
+
+impl ConnectionTracker {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            open: Mutex::new(OpenConnections::default()),
+        }
+    }
+
+    /// The connections that are open right now, in total, per ip-address and per wallet-address.
+    pub fn snapshot(&self) -> OpenConnections {
+        self.open.lock().unwrap().clone()
+    }
+
+    /// Open a connection from the ip-address, if it stays within the total and per-ip limits.
+    pub fn open(&self, ip: IpAddr) -> Result<ConnectionGuard<'_>, LimitError> {
+        let mut open = self.open.lock().unwrap();
+        if open.total >= self.limits.max_connections {
+            return Err(LimitError::Total(open.total));
+        }
+        let per_ip = open.per_ip.get(&ip).copied().unwrap_or(0);
+        if per_ip >= self.limits.max_connections_per_ip {
+            return Err(LimitError::Ip(ip, per_ip));
+        }
+
+        open.total += 1;
+        *open.per_ip.entry(ip).or_default() += 1;
+        Ok(ConnectionGuard {
+            tracker: self,
+            ip,
+            wallets: Vec::new(),
+        })
+    }
+}
+
+/// A slot of an open connection in the [`ConnectionTracker`].
+#[derive(Debug)]
+pub struct ConnectionGuard<'a> {
+    tracker: &'a ConnectionTracker,
+    ip: IpAddr,
+    wallets: Vec<Address>,
+}
+
+impl ConnectionGuard<'_> {
+    /// Register that the connection requests chunks for the wallet, if it stays within the
+    /// per-wallet limit. A wallet is only counted once per connection.
+    pub fn add_wallet(&mut self, wallet: Address) -> Result<(), LimitError> {
+        if self.wallets.contains(&wallet) {
+            return Ok(());
+        }
+        let mut open = self.tracker.open.lock().unwrap();
+        let per_wallet = open.per_wallet.get(&wallet).copied().unwrap_or(0);
+        if per_wallet >= self.tracker.limits.max_connections_per_wallet {
+            return Err(LimitError::Wallet(wallet, per_wallet));
+        }
+
+        *open.per_wallet.entry(wallet).or_default() += 1;
+        self.wallets.push(wallet);
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        let mut open = self.tracker.open.lock().unwrap();
+        open.total -= 1;
+        release(&mut open.per_ip, self.ip);
+        for wallet in &self.wallets {
+            release(&mut open.per_wallet, *wallet);
+        }
+    }
+}
+
+/// Decrease the count of the key, and remove it once no connections are left.
+fn release<K: Hash + Eq>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connections_are_limited_and_released() {
+        let tracker = ConnectionTracker::new(ConnectionLimits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            max_connections_per_wallet: 1,
+            ..Default::default()
+        });
+        let (ip1, ip2) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let wallet = Address::random();
+
+        let mut conn1 = tracker.open(ip1).unwrap();
+        let mut conn2 = tracker.open(ip1).unwrap();
+        assert_eq!(tracker.open(ip1).unwrap_err(), LimitError::Ip(ip1, 2));
+        let conn3 = tracker.open(ip2).unwrap();
+        assert_eq!(tracker.open(ip2).unwrap_err(), LimitError::Total(3));
+
+        conn1.add_wallet(wallet).unwrap();
+        conn1.add_wallet(wallet).unwrap();
+        assert_eq!(
+            conn2.add_wallet(wallet).unwrap_err(),
+            LimitError::Wallet(wallet, 1)
+        );
+
+        drop(conn1);
+        conn2.add_wallet(wallet).unwrap();
+        drop((conn2, conn3));
+        let open = tracker.snapshot();
+        assert_eq!(open.total, 0);
+        assert!(open.per_ip.is_empty() && open.per_wallet.is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use super::SharedState;
+use crate::{
+    command::{
+        self,
+        ctl::{ControlRequest, ControlResponse, DistributorStatus},
+    },
+    library::app::App,
+};
+use std::path::Path;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+/// Bind on the control-socket. A socket that is left behind by a distributor that stopped is
+/// removed, while a socket of a distributor that is still running is refused.
+pub async fn bind(path: &Path) -> eyre::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            bail!(
+                "Another distributor is running with control-socket {}",
+                path.display()
+            );
+        }
+        std::fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// Accept requests of `ctl`-commands on the control-socket.
+pub async fn serve(
+    listener: UnixListener,
+    app: &'static App,
+    shared: &'static SharedState,
+) -> eyre::Result<()> {
+    println!(
+        "Accepting control-commands on {}",
+        app.control_socket.display()
+    );
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::task::spawn(async move {
+            if let Err(e) = handle_control_connection(stream, app, shared).await {
+                eprintln!("Control-connection exited with error {e:#}.");
+            }
+        });
+    }
+}
+
+async fn handle_control_connection(
+    stream: UnixStream,
+    app: &'static App,
+    shared: &'static SharedState,
+) -> eyre::Result<()> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handle_request(request, app, shared).await,
+            Err(e) => ControlResponse::Error(format!("Invalid request: {e}")),
+        };
+        let mut line = serde_json::to_string(&response)?;
+        line.push('\n');
+        write_half.write_all(line.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn handle_request(
+    request: ControlRequest,
+    app: &'static App,
+    shared: &'static SharedState,
+) -> ControlResponse {
+    let result = match request {
+        ControlRequest::Status => status(app, shared).await.map(ControlResponse::Status),
+        ControlRequest::AddSongs { paths } => command::songs::add(paths, app)
+            .await
+            .map(|()| ControlResponse::Ok),
+        ControlRequest::Stop => {
+            println!("Stop requested over the control-socket");
+            shared.shutdown.notify_one();
+            Ok(ControlResponse::Ok)
+        }
+    };
+    result.unwrap_or_else(|e| ControlResponse::Error(format!("{e:#}")))
+}
+
+async fn status(app: &'static App, shared: &SharedState) -> eyre::Result<DistributorStatus> {
+    Ok(DistributorStatus {
+        pid: std::process::id(),
+        server_address: app.server_address.to_string(),
+        fee: app.fee().as_u32(),
+        songs: app.storage.list().await?.len(),
+        connections: shared.connections.snapshot().total,
+        outstanding_credit: shared.ledger.outstanding_credit(),
+    })
+}
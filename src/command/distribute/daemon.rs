@@ -0,0 +1,69 @@
+use std::{
+    fs::OpenOptions,
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+use tokio::{net::UnixStream, time::Instant};
+
+/// How long to wait for the distributor in the background to accept control-commands.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Start this process again without the `--daemon` flag, detached from the terminal in its own
+/// process-group, with its output appended to the log-file. The distributor can then be
+/// controlled with the `ctl`-commands.
+///
+/// This returns once the control-socket accepts connections, and fails if the distributor exits
+/// or does not accept them within the startup-timeout.
+pub async fn detach(log_path: &Path, control_socket: &Path) -> eyre::Result<()> {
+    if UnixStream::connect(control_socket).await.is_ok() {
+        bail!(
+            "Another distributor is running with control-socket {}",
+            control_socket.display()
+        );
+    }
+
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1).filter(|arg| arg != "--daemon"))
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .process_group(0)
+        .spawn()?;
+
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            bail!(
+                "Distributor exited during startup with {status}, see the log at {}",
+                log_path.display()
+            );
+        }
+        if UnixStream::connect(control_socket).await.is_ok() {
+            break;
+        }
+        if started.elapsed() > STARTUP_TIMEOUT {
+            bail!(
+                "Distributor with pid {} did not accept control-commands within {STARTUP_TIMEOUT:?}, \
+                see the log at {}",
+                child.id(),
+                log_path.display()
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    println!(
+        "Distributor started in the background with pid {}",
+        child.id()
+    );
+    println!("| log: {}", log_path.display());
+    println!("| control-socket: {}", control_socket.display());
+    Ok(())
+}
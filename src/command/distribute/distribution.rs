@@ -1,4 +1,7 @@
-use crate::library::{app::App, util::SongId};
+use crate::library::{
+    app::App,
+    util::{SongId, TransactionReceiptExt},
+};
 use itertools::Itertools;
 
 /// Distributes all songs in the database, only if we are not yet distributing them.
@@ -13,13 +16,13 @@ pub async fn distribute_songs_in_database(
     println!("Registering for all songs in database..");
 
     // Get all songs in the database
-    let song_ids = app.database.get_all_downloaded_song_ids().await?;
+    let song_ids = app.storage.list().await?;
 
     // And iterate through them by REGISTRATION_SIZE
     for chunk in song_ids.chunks(size) {
         println!("Registering for distribution of songs {song_ids:?}..",);
 
-        let songs = chunk.iter().map(|id| (*id, app.fee)).collect_vec();
+        let songs = chunk.iter().map(|id| (*id, app.fee())).collect_vec();
 
         // We try REGISTER_ATTEMPTS amount of times to distribute the song.
         // It might happen that someone else attempts to distribute the song while we are,
@@ -43,6 +46,30 @@ pub async fn distribute_songs_in_database(
     Ok(())
 }
 
+/// Registers again for all songs in the database with our current fee, which updates the fee of
+/// the songs we are already distributing. The chunk-size is `size`.
+pub async fn update_fee_of_songs_in_database(app: &'static App, size: usize) -> eyre::Result<()> {
+    println!(
+        "Updating the fee of all songs in database to {}..",
+        app.fee()
+    );
+
+    let song_ids = app.storage.list().await?;
+    for chunk in song_ids.chunks(size) {
+        let songs = chunk.iter().map(|id| (*id, app.fee())).collect_vec();
+        app.client
+            .distribute_call(songs)
+            .await?
+            .send()
+            .await?
+            .await?
+            .status_is_ok(&format!("Could not update the fee of songs {chunk:?}"))?;
+    }
+
+    println!("Updated the fee of all songs in database!\n");
+    Ok(())
+}
+
 /// Undistributes all songs in the database, only if we are not distributing them.
 /// It will try every undistribution `attempts` times, and the chunk-size is `size`.
 ///
@@ -54,7 +81,7 @@ pub async fn undistribute_songs_in_database(
 ) -> eyre::Result<()> {
     println!("Undistributing for all songs in database..");
 
-    let song_ids = app.database.get_all_downloaded_song_ids().await?;
+    let song_ids = app.storage.list().await?;
 
     let mut errors = Vec::new();
     // Send all transactions until complete or an error is encountered
@@ -0,0 +1,251 @@
+use crate::library::database::Database;
+use ethers::types::{Address, H256};
+use std::{collections::HashMap, sync::Mutex};
+
+/// The state of a get-chunks transaction of a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxState {
+    Pending,
+    Failed,
+}
+
+#[derive(Debug, Default)]
+struct ListenerDebt {
+    /// The chunks sent minus the chunks paid for. This is negative if the listener has paid for
+    /// chunks that have not been sent yet.
+    debt: i64,
+    /// The transactions of the listener that have not been confirmed, with the amount of chunks
+    /// they pay for.
+    transactions: HashMap<H256, (u32, TxState)>,
+}
+
+/// The debt of all listeners, shared across connections and keyed by the address recovered from
+/// their transactions. Every listener is allowed to be at most `debt_limit` chunks in debt.
+///
+/// If a database is given, the debt is stored there as well so that it survives restarts.
+#[derive(Debug)]
+pub struct DebtLedger {
+    debt_limit: u32,
+    listeners: Mutex<HashMap<Address, ListenerDebt>>,
+    database: Option<Database>,
+}
+
+impl DebtLedger {
+    /// Create a new ledger, which loads the stored debts if a database is given.
+    pub async fn load(debt_limit: u32, database: Option<Database>) -> eyre::Result<Self> {
+        let mut listeners = HashMap::new();
+        if let Some(database) = &database {
+            for (listener, debt) in database.get_listener_debts().await? {
+                listeners.insert(
+                    listener,
+                    ListenerDebt {
+                        debt,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+
+        Ok(Self {
+            debt_limit,
+            listeners: Mutex::new(listeners),
+            database,
+        })
+    }
+
+    /// The amount of chunks the listener is in debt.
+    pub fn debt(&self, listener: Address) -> i64 {
+        self.listeners
+            .lock()
+            .unwrap()
+            .get(&listener)
+            .map(|listener| listener.debt)
+            .unwrap_or(0)
+    }
+
+    /// The chunks sent to all listeners that have not been paid for yet.
+    pub fn outstanding_credit(&self) -> i64 {
+        self.listeners
+            .lock()
+            .unwrap()
+            .values()
+            .map(|listener| listener.debt.max(0))
+            .sum()
+    }
+
+    /// The state of a transaction of the listener, or `None` if it is not known or has been
+    /// confirmed.
+    #[cfg(test)]
+    pub fn tx_state(&self, listener: Address, hash: H256) -> Option<TxState> {
+        self.listeners
+            .lock()
+            .unwrap()
+            .get(&listener)
+            .and_then(|listener| listener.transactions.get(&hash))
+            .map(|(_chunks, state)| *state)
+    }
+
+    /// Whether the listener has transactions that failed.
+    pub fn has_failed_transactions(&self, listener: Address) -> bool {
+        self.listeners
+            .lock()
+            .unwrap()
+            .get(&listener)
+            .map(|listener| {
+                listener
+                    .transactions
+                    .values()
+                    .any(|(_chunks, state)| *state == TxState::Failed)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Take up to `wanted` chunks of credit for the listener, and add them to its debt.
+    ///
+    /// Returns the amount of chunks that may be sent.
+    pub async fn take_credit(&self, listener: Address, wanted: u32) -> eyre::Result<u32> {
+        let (taken, debt) = {
+            let mut listeners = self.listeners.lock().unwrap();
+            let entry = listeners.entry(listener).or_default();
+            let credit = (self.debt_limit as i64 - entry.debt).clamp(0, u32::MAX as i64) as u32;
+            let taken = Ord::min(credit, wanted);
+            entry.debt += taken as i64;
+            (taken, entry.debt)
+        };
+
+        if taken > 0 {
+            self.store(listener, debt).await?;
+        }
+        Ok(taken)
+    }
+
+    /// Register a transaction of the listener that pays for the given amount of chunks.
+    pub fn add_transaction(&self, listener: Address, hash: H256, chunks: u32) {
+        self.listeners
+            .lock()
+            .unwrap()
+            .entry(listener)
+            .or_default()
+            .transactions
+            .insert(hash, (chunks, TxState::Pending));
+    }
+
+    /// Mark the transaction as confirmed, which pays off the debt of the listener.
+    pub async fn confirm(&self, listener: Address, hash: H256) -> eyre::Result<()> {
+        let debt = {
+            let mut listeners = self.listeners.lock().unwrap();
+            let entry = listeners.entry(listener).or_default();
+            // Confirmed transactions are removed, so confirming twice does not pay twice.
+            entry.transactions.remove(&hash).map(|(chunks, _state)| {
+                entry.debt -= chunks as i64;
+                entry.debt
+            })
+        };
+
+        match debt {
+            Some(debt) => self.store(listener, debt).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Mark the transaction as failed, the debt of the listener remains.
+    pub fn fail(&self, listener: Address, hash: H256) {
+        if let Some(entry) = self.listeners.lock().unwrap().get_mut(&listener) {
+            if let Some((_chunks, state)) = entry.transactions.get_mut(&hash) {
+                *state = TxState::Failed;
+            }
+        }
+    }
+
+    /// Forget the transaction without paying off the debt of the listener. This is for
+    /// transactions that failed without reverting, for which the listener is not refused.
+    pub fn abandon(&self, listener: Address, hash: H256) {
+        if let Some(entry) = self.listeners.lock().unwrap().get_mut(&listener) {
+            entry.transactions.remove(&hash);
+        }
+    }
+
+    async fn store(&self, listener: Address, debt: i64) -> eyre::Result<()> {
+        if let Some(database) = &self.database {
+            database.set_listener_debt(listener, debt).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn debt_limit_is_per_address() -> eyre::Result<()> {
+        let ledger = DebtLedger::load(10, None).await?;
+        let (listener1, listener2) = (Address::random(), Address::random());
+
+        assert_eq!(ledger.take_credit(listener1, 6).await?, 6);
+        assert_eq!(ledger.take_credit(listener1, 6).await?, 4);
+        assert_eq!(ledger.take_credit(listener1, 6).await?, 0);
+        assert_eq!(ledger.take_credit(listener2, 6).await?, 6);
+        assert_eq!(ledger.debt(listener1), 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn confirmation_pays_off_debt() -> eyre::Result<()> {
+        let ledger = DebtLedger::load(10, None).await?;
+        let listener = Address::random();
+        let (hash1, hash2) = (H256::random(), H256::random());
+
+        ledger.add_transaction(listener, hash1, 10);
+        ledger.add_transaction(listener, hash2, 10);
+        assert_eq!(ledger.take_credit(listener, 20).await?, 10);
+        assert_eq!(ledger.tx_state(listener, hash1), Some(TxState::Pending));
+
+        ledger.confirm(listener, hash1).await?;
+        ledger.confirm(listener, hash1).await?;
+        assert_eq!(ledger.debt(listener), 0);
+        assert_eq!(ledger.tx_state(listener, hash1), None);
+
+        assert!(!ledger.has_failed_transactions(listener));
+        ledger.fail(listener, hash2);
+        assert_eq!(ledger.tx_state(listener, hash2), Some(TxState::Failed));
+        assert!(ledger.has_failed_transactions(listener));
+
+        // Paying before the chunks are sent gives credit beyond the limit
+        ledger.confirm(listener, hash2).await?;
+        assert_eq!(ledger.debt(listener), -10);
+        assert_eq!(ledger.take_credit(listener, 30).await?, 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn abandoned_transactions_keep_debt() -> eyre::Result<()> {
+        let ledger = DebtLedger::load(10, None).await?;
+        let (listener, hash) = (Address::random(), H256::random());
+
+        ledger.add_transaction(listener, hash, 5);
+        assert_eq!(ledger.take_credit(listener, 5).await?, 5);
+        ledger.abandon(listener, hash);
+        assert_eq!(ledger.tx_state(listener, hash), None);
+        assert!(!ledger.has_failed_transactions(listener));
+
+        ledger.confirm(listener, hash).await?;
+        assert_eq!(ledger.debt(listener), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn debt_survives_restart() -> eyre::Result<()> {
+        let database = Database::initialize_in_memory().await?;
+        let listener = Address::random();
+
+        let ledger = DebtLedger::load(10, Some(database)).await?;
+        assert_eq!(ledger.take_credit(listener, 7).await?, 7);
+        drop(ledger);
+
+        let ledger = DebtLedger::load(10, Some(database)).await?;
+        assert_eq!(ledger.debt(listener), 7);
+        assert_eq!(ledger.take_credit(listener, 7).await?, 3);
+        Ok(())
+    }
+}
@@ -1,29 +1,65 @@
 use crate::{
     arguments::Demo,
     command::distribute::{
-        background_tasks::{auto_distribute, exit_listener},
+        accounts::{AccountCache, AccountError},
+        background_tasks::{auto_distribute, exit_listener, ExitListener},
+        bandwidth::{acquire_all, RateLimiter},
+        connections::{ConnectionGuard, ConnectionTracker, LimitError},
         distribution::{distribute_songs_in_database, undistribute_songs_in_database},
+        ledger::DebtLedger,
+        nonces::{NonceTracker, ReplayError},
+        payments::{recover_pending_payments, settle_payment, Payment},
+        writer::ListenerWriter,
     },
     library::{
         abi::GetChunksCall,
         app::App,
-        tcp::{RequestChunksDecoder, SendChunksEncoder},
-        transaction_pool::TransactionPool,
-        util::{SongId, TransactionReceiptExt},
+        chunk_cache::ChunkCache,
+        limits::ConnectionLimits,
+        metrics::{self, METRICS},
+        tcp::{
+            ControlFrame, ErrorCode, FrameError, RequestChunksDecoder, RequestChunksFrame,
+            ServerAddress, MAX_CHUNKS_PER_FRAME, PROTOCOL_VERSION,
+        },
+        tls::TlsServer,
+        transaction_pool::{TransactionPool, TxFailure},
+        util::SongId,
     },
+    BYTES_PER_CHUNK,
 };
-use ethers::types::Bytes;
+use bytes::BytesMut;
+use ethers::types::{Address, Bytes};
 use ethers_providers::StreamExt;
 use eyre::Context;
-use futures::SinkExt;
+use futures::Stream;
 use std::{collections::VecDeque, convert::Infallible, net::SocketAddr, time::Duration};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{watch, Notify},
+    task::JoinSet,
+    time::Instant,
+};
+use tokio_util::codec::FramedRead;
 
+mod accounts;
+mod admin;
 mod background_tasks;
+mod bandwidth;
+mod connections;
+mod control;
+mod daemon;
 mod distribution;
+mod ledger;
+mod nonces;
+mod payments;
+#[cfg(test)]
+pub mod test_node;
+mod writer;
+
+pub use daemon::detach;
 
-/// How many chunks in debt the listener is allowed
+/// How many chunks in debt a listener is allowed, across all its connections
 const DEBT_LIMIT: u32 = 10;
 /// The amount of songs distributed at once
 const DISTR_SIZE: usize = 5;
@@ -32,6 +68,17 @@ const DISTR_ATTEMPTS: usize = 1;
 
 pub async fn distribute(app: &'static App, demo: Option<Demo>) -> eyre::Result<()> {
     let mut exit_listener = exit_listener()?;
+    let control_listener = control::bind(&app.control_socket).await?;
+
+    // Load the certificate if we accept tls-connections
+    let tls = match &app.tls {
+        Some(paths) => Some(&*Box::leak(Box::new(TlsServer::load(paths)?))),
+        None => None,
+    };
+    let server_address = ServerAddress {
+        socket_address: app.server_address,
+        tls: tls.is_some(),
+    };
 
     // Bind on the port
     println!("Binding on address {}..", app.bind_address);
@@ -39,15 +86,13 @@ pub async fn distribute(app: &'static App, demo: Option<Demo>) -> eyre::Result<(
     println!("Binding successful!\n");
 
     // Register our server address on the smart contract
-    println!(
-        "Registering address {} on smart contract..",
-        app.server_address
-    );
+    println!("Registering address {server_address} on smart contract..");
     app.client
-        .edit_server_info_call(app.server_address.to_string())
+        .edit_server_info_call(server_address.to_string())
         .send()
         .await?
         .await?;
+    METRICS.set_server_registered(true);
     println!("Registration of address succesful!\n");
 
     // And distribute all songs in the database
@@ -58,12 +103,59 @@ pub async fn distribute(app: &'static App, demo: Option<Demo>) -> eyre::Result<(
         };
     }
 
+    // Load the debt of listeners
+    let ledger = DebtLedger::load(DEBT_LIMIT, app.persist_debt.then_some(app.database)).await?;
+    let shared: &'static SharedState = Box::leak(Box::new(SharedState::new(app, ledger)));
+
+    // Serve the metrics if an address is configured
+    if let Some(address) = app.metrics_address {
+        let outstanding_credit = move || shared.ledger.outstanding_credit();
+        tokio::task::spawn(async move {
+            if let Err(e) = metrics::serve(address, outstanding_credit).await {
+                eprintln!("Serving metrics failed: {e:#}");
+            }
+        });
+    }
+
+    // Accept ctl-commands on the control-socket
+    tokio::task::spawn(async move {
+        if let Err(e) = control::serve(control_listener, app, shared).await {
+            eprintln!("Serving control-socket failed: {e:#}");
+        }
+    });
+
+    // Serve the admin-api if it is configured
+    if let Some(settings) = &app.admin {
+        tokio::task::spawn(async move {
+            if let Err(e) = admin::serve(app, shared, settings).await {
+                eprintln!("Serving admin-api failed: {e:#}");
+            }
+        });
+    }
+
+    // Settle the payments that were pending when we stopped
+    tokio::task::spawn(async move {
+        if let Err(e) = recover_pending_payments(app, shared).await {
+            eprintln!("Recovery of pending payments failed: {e:#}");
+        }
+    });
+
     // Spawn our automatic distributor
     let mut auto_distributor = tokio::task::spawn(self::auto_distribute(app, demo));
+    // The handlers of open connections, which are drained when shutting down
+    let mut handlers = JoinSet::new();
 
     let result = tokio::select! {
-        // The ctrl-c exit-handler
-        _ = &mut exit_listener => {
+        // The SIGINT and SIGTERM exit-handler
+        signal = exit_listener.recv() => {
+            println!("Received {signal}, shutting down..");
+            auto_distributor.abort();
+            let _ = auto_distributor.await;
+            Ok(())
+        }
+
+        // A shutdown requested through the admin-api or control-socket
+        _ = shared.shutdown.notified() => {
             auto_distributor.abort();
             let _ = auto_distributor.await;
             Ok(())
@@ -75,7 +167,7 @@ pub async fn distribute(app: &'static App, demo: Option<Demo>) -> eyre::Result<(
         }
 
         // The main process that handles incoming connections
-        res = accept_tcp_connections(listener, app) => {
+        res = accept_tcp_connections(listener, app, shared, tls, &mut handlers) => {
             auto_distributor.abort();
             let _ = auto_distributor.await;
             match res {
@@ -85,8 +177,18 @@ pub async fn distribute(app: &'static App, demo: Option<Demo>) -> eyre::Result<(
         }
     };
 
-    // And for graceful shutdown we undistribute all songs
-    match (undistribute_songs_in_database(app, 3, 5).await, result) {
+    // For graceful shutdown new connections are not accepted anymore, and we undistribute all
+    // songs before the open connections are drained.
+    let undistributed = undistribute_songs_in_database(app, 3, 5).await;
+    drain_connections(
+        shared,
+        &mut handlers,
+        &mut exit_listener,
+        app.limits.shutdown_timeout,
+    )
+    .await;
+    let _ = std::fs::remove_file(&app.control_socket);
+    match (undistributed, result) {
         (Ok(_), Ok(_)) => Ok(()),
         (Ok(_), Err(e)) => Err(e),
         (Err(e), Ok(_)) => Err(e),
@@ -94,16 +196,108 @@ pub async fn distribute(app: &'static App, demo: Option<Demo>) -> eyre::Result<(
     }
 }
 
-/// Accept incoming tcp-connections and spawn processes to handle them.
-pub async fn accept_tcp_connections(
+/// The state that is shared by all connections with listeners.
+struct SharedState {
+    ledger: DebtLedger,
+    connections: ConnectionTracker,
+    nonces: NonceTracker,
+    /// The bandwidth across all connections, if it is limited.
+    bandwidth: Option<RateLimiter>,
+    /// The chunks that were served recently.
+    chunk_cache: ChunkCache,
+    /// Notified when the distributor should shut down gracefully.
+    shutdown: Notify,
+    /// Set when shutting down, after which connections finish their open requests and payments
+    /// and then close.
+    draining: watch::Sender<bool>,
+}
+
+impl SharedState {
+    fn new(app: &App, ledger: DebtLedger) -> Self {
+        Self {
+            ledger,
+            connections: ConnectionTracker::new(app.limits),
+            nonces: NonceTracker::new(),
+            bandwidth: app
+                .limits
+                .bandwidth
+                .map(|bytes_per_sec| RateLimiter::new(bytes_per_sec, BYTES_PER_CHUNK.into())),
+            chunk_cache: ChunkCache::new(app.chunk_cache_size),
+            shutdown: Notify::new(),
+            draining: watch::channel(false).0,
+        }
+    }
+}
+
+/// Let the open connections finish their requests and payments, until the timeout passes or
+/// another exit-signal arrives. Payments that are still pending then are sent again the next time
+/// distribution starts.
+async fn drain_connections(
+    shared: &SharedState,
+    handlers: &mut JoinSet<()>,
+    exit_listener: &mut ExitListener,
+    timeout: Duration,
+) {
+    let _ = shared.draining.send(true);
+    if handlers.is_empty() {
+        return;
+    }
+
+    println!(
+        "Waiting up to {timeout:?} for {} open connections to finish..",
+        handlers.len()
+    );
+    let finished = tokio::select! {
+        _ = async { while handlers.join_next().await.is_some() {} } => true,
+        _ = tokio::time::sleep(timeout) => false,
+        signal = exit_listener.recv() => {
+            println!("Received {signal} again, not waiting any longer");
+            false
+        }
+    };
+
+    if finished {
+        println!("All open connections finished");
+    } else {
+        println!(
+            "Closing {} connections, their pending payments are sent on the next start",
+            handlers.len()
+        );
+        handlers.shutdown().await;
+    }
+}
+
+/// Accept incoming tcp-connections and spawn processes to handle them on the join-set. If a
+/// tls-server is given, then all connections must use TLS. Connections over the limits of the
+/// tracker are refused.
+async fn accept_tcp_connections(
     listener: TcpListener,
     app: &'static App,
+    shared: &'static SharedState,
+    tls: Option<&'static TlsServer>,
+    handlers: &mut JoinSet<()>,
 ) -> eyre::Result<Infallible> {
     println!("Accepting connections on {}", app.bind_address);
     loop {
-        let (mut stream, addr) = listener.accept().await?;
-        tokio::task::spawn(async move {
-            match handle_new_connection(&mut stream, addr, app).await {
+        // Handlers that finished are removed from the join-set while waiting
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            Some(_) = handlers.join_next() => continue,
+        };
+        let connection = shared.connections.open(addr.ip());
+        handlers.spawn(async move {
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => {
+                        let cert_hash = Some(tls.cert_hash());
+                        handle_new_connection(stream, addr, app, shared, connection, cert_hash)
+                            .await
+                    }
+                    Err(e) => Err(e.wrap_err("TLS-handshake failed")),
+                },
+                None => handle_new_connection(stream, addr, app, shared, connection, None).await,
+            };
+            match result {
                 Ok(()) => (),
                 Err(e) => eprintln!("Handler {addr} exited with error {e:#}."),
             }
@@ -111,81 +305,307 @@ pub async fn accept_tcp_connections(
     }
 }
 
-/// Handle a new tcp-connection.
+/// Handle a new connection, which is refused as busy if it was over the connection-limits. For
+/// TLS-connections, the channel-binding is the hash of our certificate, which is included in the
+/// proof of our address.
+///
+/// However the connection ends, the payments for the chunks that were sent on credit are settled
+/// after it closed, so that they still pay off the debt of the listener.
 async fn handle_new_connection(
-    stream: &mut TcpStream,
+    stream: impl AsyncRead + AsyncWrite,
     addr: SocketAddr,
     app: &'static App,
+    shared: &'static SharedState,
+    connection: Result<ConnectionGuard<'static>, LimitError>,
+    channel_binding: Option<[u8; 32]>,
 ) -> eyre::Result<()> {
     println!("Accepted connetion from {addr}");
-
-    // The amount of credit in chunks
-    let mut credit: u32 = DEBT_LIMIT;
-    // Queue of client chunk-requests
-    let mut open_requests: VecDeque<GetChunksCall> = VecDeque::new();
-    // The transactions that resolves to the amount of credit.
+    // The transactions that pay off the debt of the listeners.
     let mut transaction_pool = TransactionPool::new(&app.client, Duration::from_millis(100), 7);
+    let result = serve_connection(
+        stream,
+        addr,
+        app,
+        shared,
+        connection,
+        channel_binding,
+        &mut transaction_pool,
+    )
+    .await;
+
+    if !transaction_pool.is_empty() {
+        println!("Settling the pending payments of closed connection {addr}");
+    }
+    while let Some((tx_result, payment)) = transaction_pool.next().await {
+        if let Err(e) = settle_payment(app, shared, payment, tx_result).await {
+            eprintln!(
+                "Settling payment {:?} of {addr} failed: {e:#}",
+                payment.tx_hash
+            );
+        }
+    }
+    result
+}
+
+/// Serve the requests of the listener until the connection closes or is refused.
+async fn serve_connection(
+    stream: impl AsyncRead + AsyncWrite,
+    addr: SocketAddr,
+    app: &'static App,
+    shared: &'static SharedState,
+    connection: Result<ConnectionGuard<'static>, LimitError>,
+    channel_binding: Option<[u8; 32]>,
+    transaction_pool: &mut TransactionPool<Payment>,
+) -> eyre::Result<()> {
+    let _active = METRICS.open_connection();
+    let ledger = &shared.ledger;
+    let mut draining = shared.draining.subscribe();
+
+    // Queue of client chunk-requests, with the listener that sent them
+    let mut open_requests: VecDeque<(Address, GetChunksCall)> = VecDeque::new();
+    // The accounts of the listeners, to check whether they can pay for their requests.
+    let mut accounts = AccountCache::new(&app.client);
 
     // Construct the tcp-stream
-    let stream = stream.split();
-    let mut tcp_reader = FramedRead::new(stream.0, RequestChunksDecoder::new());
-    let mut tcp_writer = FramedWrite::new(stream.1, SendChunksEncoder);
+    let limits = app.limits;
+    let stream = tokio::io::split(stream);
+    let decoder = RequestChunksDecoder::with_max_len(limits.max_request_len);
+    let mut tcp_reader = FramedRead::new(stream.0, decoder);
+    let mut tcp_writer = ListenerWriter::new(stream.1, addr, limits.write_timeout);
+    // The bandwidth of this connection, which is shared fairly with the other connections.
+    let connection_bandwidth = limits
+        .bandwidth_per_connection
+        .map(|bytes_per_sec| RateLimiter::new(bytes_per_sec, BYTES_PER_CHUNK.into()));
+
+    // Refuse connections over the limits before the handshake, so they are closed right away.
+    let mut connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => return refuse_busy(&mut tcp_reader, &mut tcp_writer, addr, limits, e).await,
+    };
+
+    // Start with the handshake, which tells us which features the listener supports.
+    let handshake = handshake(&mut tcp_reader, &mut tcp_writer, addr);
+    let Ok(handshake) = tokio::time::timeout(limits.idle_timeout, handshake).await else {
+        println!(
+            "Closing connection with {addr}, no handshake after {:?}",
+            limits.idle_timeout
+        );
+        return Ok(());
+    };
+    let Some(mut first_request) = handshake? else {
+        return Ok(());
+    };
+
+    // When something last happened on the connection.
+    let mut last_activity = Instant::now();
+    // When the connection went into debt, without any of its transactions confirming since.
+    let mut in_debt_since: Option<Instant> = None;
 
     // An repeatedly wait for messages to arrive over tcp or for a transaction to complete from the pool.
     'outer: loop {
-        let tcp_msg = tokio::select! {
-            Some(result) = transaction_pool.next() => {
-                let (receipt, new_credit) = result?;
-                receipt.status_is_ok("").wrap_err(format!("From request-chunks transaction of {addr}"))?;
-                credit = credit.checked_add(new_credit).unwrap();
-                None
-            }
-
-            res = tcp_reader.next() => {
-                match res {
-                    Some(msg) => {
-                        Some(msg)
-                    },
-                    None => break 'outer Ok(())
+        let tcp_msg = if let Some(request) = first_request.take() {
+            Some(Ok(RequestChunksFrame::GetChunks(request)))
+        } else {
+            // When shutting down no new requests are read, and the connection is closed once
+            // its open requests are served and its payments are settled.
+            let is_draining = *draining.borrow();
+            if is_draining && open_requests.is_empty() && transaction_pool.is_empty() {
+                println!("Closing connection with {addr} for shutdown");
+                break 'outer Ok(());
+            }
+
+            let timeout = next_timeout(
+                &limits,
+                last_activity,
+                tcp_reader.decoder().partial_frame_since(),
+                in_debt_since,
+                open_requests.is_empty() && transaction_pool.is_empty(),
+            );
+
+            tokio::select! {
+                Some((result, payment)) = transaction_pool.next() => {
+                    last_activity = Instant::now();
+                    // Only listeners whose transactions revert are refused, other failures leave
+                    // the debt until it is paid or times out.
+                    let settled = settle_payment(app, shared, payment, result).await?;
+                    accounts.settle(payment.listener, payment.tx_hash);
+                    match settled {
+                        None => {
+                            let debt = ledger.debt(payment.listener);
+                            in_debt_since = (debt > 0).then_some(last_activity);
+                        }
+                        Some(failure @ TxFailure::Reverted(_)) => {
+                            return tcp_writer.refuse(ErrorCode::BadTransaction, failure).await;
+                        }
+                        Some(_) => (),
+                    }
+                    None
+                }
+
+                _ = draining.changed(), if !is_draining => None,
+
+                res = tcp_reader.next(), if !is_draining => {
+                    last_activity = Instant::now();
+                    match res {
+                        Some(msg) => {
+                            Some(msg)
+                        },
+                        None => break 'outer Ok(())
+                    }
+                }
+
+                Some(timeout) = sleep_until(timeout) => {
+                    match timeout {
+                        Timeout::Idle => {
+                            println!("Closing idle connection with {addr}");
+                            break 'outer Ok(())
+                        }
+                        Timeout::Read => {
+                            let timeout = limits.read_timeout;
+                            bail!("Timed out after {timeout:?} receiving a frame from {addr}")
+                        }
+                        Timeout::Debt => {
+                            let timeout = limits.max_debt_duration;
+                            let msg = format!("No payment confirmed within {timeout:?}");
+                            return tcp_writer.refuse(ErrorCode::DebtExceeded, msg).await
+                        }
+                    }
                 }
             }
         };
 
         // If the event was a tcp-message, then we have to verify it.
         if let Some(tcp_msg) = tcp_msg {
-            let tx = Bytes(
-                tcp_msg
-                    .wrap_err(format!("Custom tcp-protocol not folowed by {addr}"))?
-                    .freeze(),
-            );
+            let raw_tx =
+                match tcp_msg.wrap_err(format!("Custom tcp-protocol not folowed by {addr}"))? {
+                    RequestChunksFrame::GetChunks(rlp) => Bytes(rlp.freeze()),
+                    RequestChunksFrame::Control(ControlFrame::Challenge { nonce }) => {
+                        let signature = app
+                            .wallet
+                            .sign_challenge(&nonce, channel_binding.as_ref())
+                            .await?;
+                        tcp_writer.send(&ControlFrame::Proof { signature }).await?;
+                        continue 'outer;
+                    }
+                    RequestChunksFrame::Control(frame) => {
+                        bail!("Unexpected control-frame from {addr}: {frame:?}")
+                    }
+                };
 
-            // Decode the chunk-request paramaters
-            let params = app.client.decode_get_chunks_params(&tx)?;
-            if params.distributor != app.client.wallet_address() {
-                bail!(
-                    "Distributor address is not my address!: {}, {}",
-                    params.distributor,
-                    app.client.wallet_address()
-                )
-            }
+            // Validate the transaction before giving any credit for it
+            let tx = match app.client.validate_get_chunks_tx(raw_tx) {
+                Ok(tx) => tx,
+                Err(e) => return tcp_writer.refuse(ErrorCode::BadTransaction, e).await,
+            };
+            let params = tx.params.clone();
 
             println!(
-                "Received get-chunks from {} for song {} with index {} and amount {}",
+                "Received get-chunks from {} ({:?}) for song {} with index {} and amount {}",
                 addr,
+                tx.sender,
                 SongId::from(params.song),
                 params.index,
                 params.amount
             );
 
+            // Refuse listeners that use too many connections at once
+            if let Err(e) = connection.add_wallet(tx.sender) {
+                return tcp_writer.refuse(ErrorCode::Busy, e).await;
+            }
+
+            // Refuse requests for songs we don't hold or that run past the end of the song
+            let song_id = SongId::from(params.song);
+            let Some(song_chunks) = app.storage.chunk_count(&song_id).await? else {
+                let msg = format!("Song {song_id} is not stored");
+                return tcp_writer.refuse(ErrorCode::SongNotHeld, msg).await;
+            };
+            if params.index + params.amount > song_chunks.into() {
+                let msg = format!(
+                    "Chunks {} to {} requested, song {song_id} has {song_chunks} chunks",
+                    params.index,
+                    params.index + params.amount - 1,
+                );
+                return tcp_writer.refuse(ErrorCode::OutOfRange, msg).await;
+            }
+
+            // Refuse listeners whose payments have failed before
+            if ledger.has_failed_transactions(tx.sender) {
+                let msg = format!(
+                    "Listener {:?} has {} chunks of unpaid debt",
+                    tx.sender,
+                    ledger.debt(tx.sender)
+                );
+                return tcp_writer.refuse(ErrorCode::DebtExceeded, msg).await;
+            }
+
+            // Refuse the listener if they cannot pay for the request
+            match accounts.check(&tx).await {
+                Ok(()) => (),
+                Err(AccountError::Lookup(e)) => {
+                    let _ = tcp_writer.refuse(ErrorCode::Internal, "").await;
+                    return Err(e.wrap_err(format!("Could not check the account of {addr}")));
+                }
+                Err(e) => return tcp_writer.refuse(e.error_code(), e).await,
+            }
+
+            // Refuse replayed transactions and nonces, which would pay for multiple requests.
+            if !shared.nonces.contains(tx.sender) {
+                match app.client.get_transaction_count(tx.sender).await {
+                    Ok(nonce) => shared.nonces.insert(tx.sender, nonce),
+                    Err(e) => {
+                        let _ = tcp_writer.refuse(ErrorCode::Internal, "").await;
+                        return Err(e.wrap_err(format!("Could not look up the nonce of {addr}")));
+                    }
+                }
+            }
+            if let Err(e) = shared.nonces.check(&tx) {
+                return tcp_writer.refuse(ErrorCode::BadTransaction, e).await;
+            }
+
+            // Store the transaction before any chunks are sent on credit, so that it is sent again
+            // after a crash.
+            let payment = Payment {
+                listener: tx.sender,
+                tx_hash: tx.hash,
+                song: song_id,
+                chunks: params.amount.as_u32(),
+                fee: accounts.fee_for_chunks(song_id, params.amount),
+            };
+            app.database
+                .add_pending_payment(
+                    tx.sender,
+                    tx.hash,
+                    &tx.raw,
+                    &payment.song,
+                    payment.chunks,
+                    payment.fee,
+                )
+                .await?;
+
+            // The nonce is only reserved once the transaction is stored, so that it can be used
+            // again if storing fails. Another connection may have reserved it in the meantime.
+            match shared.nonces.reserve(&tx) {
+                Ok(()) => (),
+                // The stored transaction is the one of the other connection.
+                Err(e @ ReplayError::Duplicate(_)) => {
+                    return tcp_writer.refuse(ErrorCode::BadTransaction, e).await
+                }
+                Err(e) => {
+                    app.database.remove_pending_payment(tx.hash).await?;
+                    return tcp_writer.refuse(ErrorCode::BadTransaction, e).await;
+                }
+            }
+
             // And push the request and pending transaction to the lists.
-            transaction_pool.push_raw_tx(tx, params.amount.as_u128().try_into()?);
-            open_requests.push_back(params);
+            ledger.add_transaction(tx.sender, tx.hash, payment.chunks);
+            transaction_pool.push_raw_tx(tx.raw, payment);
+            open_requests.push_back((tx.sender, params));
         };
 
-        // Now we can send chunks until the credit runs out.
-        'credit: while credit > 0 {
+        // Now we can send chunks until the credit of the listener runs out.
+        'credit: loop {
             // Check the next open request
-            let Some(params) = open_requests.front_mut() else {
+            let Some((listener, params)) = open_requests.front_mut() else {
                 continue 'outer;
             };
 
@@ -195,25 +615,396 @@ async fn handle_new_connection(
                 continue 'credit;
             }
 
-            // Update the current open request, and select how much to stream right now.
+            // Update the current open request, and select how much to stream right now. This is
+            // never more than fits in a single frame.
             let (amount, index) = {
-                let amount = Ord::min(credit, params.amount.as_u32());
+                let wanted = Ord::min(params.amount.as_u32(), MAX_CHUNKS_PER_FRAME);
+                let amount = ledger.take_credit(*listener, wanted).await?;
+                if amount == 0 {
+                    continue 'outer;
+                }
                 let index = params.index.as_u32();
 
-                credit -= amount;
                 params.amount -= amount.into();
                 params.index += amount.into();
 
                 (amount, index)
             };
 
-            // Get the chunks from the database and send over tcp
-            let chunks = app
-                .database
-                .get_chunks(&params.song.into(), index, amount)
+            // Get the chunks from the cache or database and send over tcp
+            let chunks = shared
+                .chunk_cache
+                .get_chunks(&*app.storage, &params.song.into(), index, amount)
                 .await?;
+            let limiters = [connection_bandwidth.as_ref(), shared.bandwidth.as_ref()];
+            acquire_all(&limiters, chunks.len() as u64).await;
             println!("Sending {amount} chunks starting at {index} to {addr}.");
+            let bytes = chunks.len();
             tcp_writer.send((index, &chunks.into())).await?;
+            METRICS.add_chunks_served(params.song.into(), amount, bytes);
+            last_activity = Instant::now();
+            in_debt_since.get_or_insert(last_activity);
+        }
+    }
+}
+
+/// Wait for the first frame of the listener, which is either a hello-frame or a get-chunks request
+/// from a listener that speaks the legacy protocol without a handshake. This sets the features of
+/// the listener on the writer.
+///
+/// Returns the first request for legacy listeners. Returns `None` if the listener closed the
+/// connection or was refused.
+async fn handshake<R, W>(
+    tcp_reader: &mut R,
+    tcp_writer: &mut ListenerWriter<W>,
+    addr: SocketAddr,
+) -> eyre::Result<Option<Option<BytesMut>>>
+where
+    R: Stream<Item = Result<RequestChunksFrame, FrameError>> + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let Some(frame) = tcp_reader.next().await else {
+        return Ok(None);
+    };
+
+    match frame.wrap_err(format!("Custom tcp-protocol not folowed by {addr}"))? {
+        RequestChunksFrame::GetChunks(rlp) => {
+            println!("Listener {addr} speaks the legacy protocol");
+            Ok(Some(Some(rlp)))
+        }
+        RequestChunksFrame::Control(ControlFrame::Hello {
+            version,
+            software,
+            features,
+        }) => {
+            tcp_writer.features = features;
+            if version != PROTOCOL_VERSION {
+                let msg = format!(
+                    "Protocol version {version} is not supported, expected {PROTOCOL_VERSION}"
+                );
+                tcp_writer
+                    .refuse(ErrorCode::UnsupportedVersion, msg)
+                    .await?;
+                return Ok(None);
+            }
+            println!("Listener {addr} runs version {software} with features {features:?}");
+            tcp_writer.send(&ControlFrame::welcome()).await?;
+            Ok(Some(None))
+        }
+        RequestChunksFrame::Control(frame) => {
+            bail!("Expected a hello-frame from {addr}, got {frame:?}")
+        }
+    }
+}
+
+/// Refuse a connection over the limits. Only listeners that announce support for error-frames in
+/// their hello-frame are sent an error, since legacy listeners would read it as a chunk-frame.
+/// Others only see the connection close.
+async fn refuse_busy<R, W>(
+    tcp_reader: &mut R,
+    tcp_writer: &mut ListenerWriter<W>,
+    addr: SocketAddr,
+    limits: ConnectionLimits,
+    error: LimitError,
+) -> eyre::Result<()>
+where
+    R: Stream<Item = Result<RequestChunksFrame, FrameError>> + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let first_frame = tokio::time::timeout(limits.read_timeout, tcp_reader.next()).await;
+    match first_frame {
+        Ok(Some(Ok(RequestChunksFrame::Control(ControlFrame::Hello { features, .. })))) => {
+            tcp_writer.features = features;
+            tcp_writer.refuse(ErrorCode::Busy, error).await
+        }
+        _ => {
+            println!("Refusing connection from {addr}: {error}");
+            Ok(())
+        }
+    }
+}
+
+/// A timeout that closes the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Timeout {
+    /// Nothing happened on the connection for too long.
+    Idle,
+    /// The listener took too long to send a frame.
+    Read,
+    /// The connection was in debt for too long without any payment confirming.
+    Debt,
+}
+
+/// The timeout of the connection that expires first, if any.
+fn next_timeout(
+    limits: &ConnectionLimits,
+    last_activity: Instant,
+    partial_frame_since: Option<Instant>,
+    in_debt_since: Option<Instant>,
+    is_idle: bool,
+) -> Option<(Instant, Timeout)> {
+    [
+        is_idle.then_some((last_activity + limits.idle_timeout, Timeout::Idle)),
+        partial_frame_since.map(|since| (since + limits.read_timeout, Timeout::Read)),
+        in_debt_since.map(|since| (since + limits.max_debt_duration, Timeout::Debt)),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by_key(|(deadline, _)| *deadline)
+}
+
+/// Sleep until the deadline of the timeout, or forever if there is none.
+async fn sleep_until(timeout: Option<(Instant, Timeout)>) -> Option<Timeout> {
+    match timeout {
+        Some((deadline, timeout)) => {
+            tokio::time::sleep_until(deadline).await;
+            Some(timeout)
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{test_node::TestNode, *};
+    use crate::{
+        library::{
+            client::{TangleTunesClient, WEI_PER_IOTA},
+            crypto::Wallet,
+            tcp::{RequestChunksEncoder, SendChunksDecoder, SendChunksFrame},
+        },
+        test,
+    };
+    use futures::SinkExt;
+    use tokio::{
+        io::{DuplexStream, ReadHalf, WriteHalf},
+        task::JoinHandle,
+    };
+    use tokio_util::codec::FramedWrite;
+
+    /// A distributor that stores a song and uses the test-node.
+    async fn distributor(node: &TestNode) -> eyre::Result<(&'static App, &'static SharedState)> {
+        let app = App::init_with_node(&node.url).await?;
+        let song = SongId::try_from_hex(test::HEX_ID_1)?;
+        let data = std::fs::read(format!("mp3/{}.mp3", test::HEX_ID_1))?;
+        app.storage.put(&song, &data).await?;
+        let ledger = DebtLedger::load(DEBT_LIMIT, None).await?;
+        Ok((app, Box::leak(Box::new(SharedState::new(app, ledger)))))
+    }
+
+    /// A listener that is connected to a handler of the distributor, after the handshake.
+    struct TestListener {
+        requests: FramedWrite<WriteHalf<DuplexStream>, RequestChunksEncoder>,
+        responses: FramedRead<ReadHalf<DuplexStream>, SendChunksDecoder>,
+        handler: JoinHandle<eyre::Result<()>>,
+    }
+
+    impl TestListener {
+        async fn connect(app: &'static App, shared: &'static SharedState) -> eyre::Result<Self> {
+            let addr: SocketAddr = "127.0.0.1:1".parse()?;
+            let (distributor, listener) = tokio::io::duplex(1 << 20);
+            let connection = shared.connections.open(addr.ip());
+            let handler = tokio::spawn(handle_new_connection(
+                distributor,
+                addr,
+                app,
+                shared,
+                connection,
+                None,
+            ));
+
+            let (reader, writer) = tokio::io::split(listener);
+            let mut listener = Self {
+                requests: FramedWrite::new(writer, RequestChunksEncoder),
+                responses: FramedRead::new(reader, SendChunksDecoder::default()),
+                handler,
+            };
+            listener.requests.send(&ControlFrame::hello()).await?;
+            match listener.next_frame().await? {
+                SendChunksFrame::Control(ControlFrame::Welcome { .. }) => Ok(listener),
+                frame => bail!("Expected a welcome-frame, got {frame:?}"),
+            }
+        }
+
+        async fn next_frame(&mut self) -> eyre::Result<SendChunksFrame> {
+            let frame = tokio::time::timeout(Duration::from_secs(10), self.responses.next()).await;
+            match frame {
+                Ok(Some(frame)) => Ok(frame?),
+                Ok(None) => bail!("Connection closed"),
+                Err(_) => bail!("No frame received"),
+            }
+        }
+
+        /// Pay for chunks of the song and check that they are sent.
+        async fn request_chunks(
+            &mut self,
+            client: &TangleTunesClient,
+            distributor: Address,
+            index: u32,
+        ) -> eyre::Result<()> {
+            let song = SongId::try_from_hex(test::HEX_ID_1)?;
+            let amount = MAX_CHUNKS_PER_FRAME;
+            let raw = client
+                .create_get_chunks_signed_rlp(song, index as usize, amount as usize, distributor)
+                .await?;
+            self.requests.send(&raw.0).await?;
+            match self.next_frame().await? {
+                SendChunksFrame::Chunks(start, chunks) => {
+                    assert_eq!(start, index);
+                    assert_eq!(chunks.len(), (amount * BYTES_PER_CHUNK) as usize);
+                    Ok(())
+                }
+                frame => bail!("Expected chunks, got {frame:?}"),
+            }
+        }
+
+        /// Close the connection, and wait until the handler settled its payments.
+        async fn disconnect(self, node: &TestNode) -> eyre::Result<()> {
+            drop((self.requests, self.responses));
+            node.mine();
+            tokio::time::timeout(Duration::from_secs(10), self.handler).await???;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn listener_is_served_again_after_reconnecting() -> eyre::Result<()> {
+        let node = TestNode::start().await?;
+        let (app, shared) = distributor(&node).await?;
+        let wallet = Wallet::generate(test::CHAIN_ID);
+        let client = TangleTunesClient::initialize_offline(wallet, &app.contract_address)?;
+        let (listener, distributor) = (client.wallet_address(), app.client.wallet_address());
+
+        // The listener uses all of its credit and disconnects before paying for it.
+        let mut connection = TestListener::connect(app, shared).await?;
+        connection.request_chunks(&client, distributor, 0).await?;
+        assert_eq!(shared.ledger.debt(listener), DEBT_LIMIT as i64);
+        connection.disconnect(&node).await?;
+        assert_eq!(shared.ledger.debt(listener), 0);
+
+        // So after reconnecting, it gets credit again.
+        let mut connection = TestListener::connect(app, shared).await?;
+        connection.request_chunks(&client, distributor, 10).await?;
+        connection.disconnect(&node).await?;
+        assert_eq!(shared.ledger.debt(listener), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_payments_are_settled_after_disconnecting() -> eyre::Result<()> {
+        let node = TestNode::start().await?;
+        let (app, shared) = distributor(&node).await?;
+        let wallet = Wallet::generate(test::CHAIN_ID);
+        let client = TangleTunesClient::initialize_offline(wallet, &app.contract_address)?;
+        let (listener, distributor) = (client.wallet_address(), app.client.wallet_address());
+
+        let mut connection = TestListener::connect(app, shared).await?;
+        connection.request_chunks(&client, distributor, 0).await?;
+        let pending = app.database.get_pending_payments().await?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            (pending[0].0, pending[0].3),
+            (listener, MAX_CHUNKS_PER_FRAME)
+        );
+
+        // The payment is settled without restarting the distributor.
+        connection.disconnect(&node).await?;
+        assert!(app.database.get_pending_payments().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn last_request_is_earned_after_disconnecting() -> eyre::Result<()> {
+        let node = TestNode::start().await?;
+        let (app, shared) = distributor(&node).await?;
+        let wallet = Wallet::generate(test::CHAIN_ID);
+        let client = TangleTunesClient::initialize_offline(wallet, &app.contract_address)?;
+        let (listener, distributor) = (client.wallet_address(), app.client.wallet_address());
+        let chunks = MAX_CHUNKS_PER_FRAME as u64;
+        let fee = chunks * (test_node::FEE_PER_CHUNK / WEI_PER_IOTA) as u64;
+
+        let mut connection = TestListener::connect(app, shared).await?;
+        connection.request_chunks(&client, distributor, 0).await?;
+        assert!(app.database.get_earnings_per_listener().await?.is_empty());
+        connection.disconnect(&node).await?;
+        assert_eq!(
+            app.database.get_earnings_per_listener().await?,
+            vec![(listener, 1, chunks, fee)]
+        );
+
+        let mut connection = TestListener::connect(app, shared).await?;
+        connection.request_chunks(&client, distributor, 10).await?;
+        connection.disconnect(&node).await?;
+        assert_eq!(
+            app.database.get_earnings_per_listener().await?,
+            vec![(listener, 2, 2 * chunks, 2 * fee)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn earliest_timeout_expires_first() {
+        let limits = ConnectionLimits {
+            idle_timeout: Duration::from_secs(60),
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            max_debt_duration: Duration::from_secs(120),
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        assert_eq!(next_timeout(&limits, now, None, None, false), None);
+        assert_eq!(
+            next_timeout(&limits, now, None, None, true),
+            Some((now + limits.idle_timeout, Timeout::Idle))
+        );
+        assert_eq!(
+            next_timeout(&limits, now, Some(now), None, true),
+            Some((now + limits.read_timeout, Timeout::Read))
+        );
+        let later = now + Duration::from_secs(115);
+        assert_eq!(
+            next_timeout(&limits, later, Some(later), Some(now), false),
+            Some((now + limits.max_debt_duration, Timeout::Debt))
+        );
+    }
+
+    #[tokio::test]
+    async fn busy_error_is_only_sent_with_error_frames() -> eyre::Result<()> {
+        let addr = "127.0.0.1:1".parse()?;
+        let limits = ConnectionLimits::default();
+        let legacy_request = bytes::Bytes::from(vec![1, 2, 3]);
+
+        for (hello, expect_error) in [(true, true), (false, false)] {
+            let (distributor, listener) = tokio::io::duplex(1024);
+            let (listener_reader, listener_writer) = tokio::io::split(listener);
+            let mut requests = FramedWrite::new(listener_writer, RequestChunksEncoder);
+            match hello {
+                true => requests.send(&ControlFrame::hello()).await?,
+                false => requests.send(&legacy_request).await?,
+            }
+
+            let (reader, writer) = tokio::io::split(distributor);
+            let mut tcp_reader = FramedRead::new(reader, RequestChunksDecoder::default());
+            let mut tcp_writer = ListenerWriter::new(writer, addr, limits.write_timeout);
+            let error = LimitError::Total(1);
+            refuse_busy(&mut tcp_reader, &mut tcp_writer, addr, limits, error).await?;
+            drop((tcp_reader, tcp_writer));
+
+            let frames: Vec<_> = FramedRead::new(listener_reader, SendChunksDecoder::default())
+                .collect()
+                .await;
+            match expect_error {
+                true => assert!(matches!(
+                    &frames[..],
+                    [Ok(SendChunksFrame::Control(ControlFrame::Error {
+                        code: ErrorCode::Busy,
+                        ..
+                    }))]
+                )),
+                false => assert!(frames.is_empty()),
+            }
         }
+        Ok(())
     }
 }
@@ -0,0 +1,243 @@
+use crate::library::client::GetChunksTx;
+use ethers::types::{Address, H256, U256};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+/// Why a transaction is refused as a replay.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ReplayError {
+    #[error("Transaction {0:?} has already been received")]
+    Duplicate(H256),
+    #[error("Nonce {nonce} of {listener:?} has already been used, expected {expected}")]
+    NonceReused {
+        listener: Address,
+        nonce: U256,
+        expected: U256,
+    },
+    #[error("Nonce {nonce} of {listener:?} skips ahead of the expected nonce {expected}")]
+    NonceGap {
+        listener: Address,
+        nonce: U256,
+        expected: U256,
+    },
+}
+
+#[derive(Debug)]
+struct ListenerNonces {
+    /// The nonce the next transaction of the listener must have.
+    next: U256,
+    /// The transactions of the listener that have been received.
+    received: HashSet<H256>,
+}
+
+impl ListenerNonces {
+    fn new(next: U256) -> Self {
+        Self {
+            next,
+            received: HashSet::new(),
+        }
+    }
+
+    /// Check that the transaction has the next nonce. A transaction that skips a nonce is never
+    /// mined, so it would not pay for the chunks it is served.
+    fn check(&self, tx: &GetChunksTx) -> Result<(), ReplayError> {
+        if self.received.contains(&tx.hash) {
+            return Err(ReplayError::Duplicate(tx.hash));
+        }
+        if tx.nonce < self.next {
+            return Err(ReplayError::NonceReused {
+                listener: tx.sender,
+                nonce: tx.nonce,
+                expected: self.next,
+            });
+        }
+        if tx.nonce > self.next {
+            return Err(ReplayError::NonceGap {
+                listener: tx.sender,
+                nonce: tx.nonce,
+                expected: self.next,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The nonces of the transactions of all listeners, shared across connections. Every transaction
+/// of a listener must have the next nonce, so that the same transaction or nonce cannot be used to
+/// pay for multiple requests.
+#[derive(Debug, Default)]
+pub struct NonceTracker {
+    listeners: Mutex<HashMap<Address, ListenerNonces>>,
+}
+
+impl NonceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the next nonce of the listener is known.
+    pub fn contains(&self, listener: Address) -> bool {
+        self.listeners.lock().unwrap().contains_key(&listener)
+    }
+
+    /// Set the next nonce of the listener, as looked up on-chain, if it is not yet known.
+    pub fn insert(&self, listener: Address, next: U256) {
+        self.listeners
+            .lock()
+            .unwrap()
+            .entry(listener)
+            .or_insert_with(|| ListenerNonces::new(next));
+    }
+
+    /// Check that the transaction has the next nonce of its sender, without reserving it.
+    pub fn check(&self, tx: &GetChunksTx) -> Result<(), ReplayError> {
+        match self.listeners.lock().unwrap().get(&tx.sender) {
+            Some(listener) => listener.check(tx),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the transaction has the next nonce of its sender, and reserve it. Listeners
+    /// that are not known start at the nonce of this transaction.
+    pub fn reserve(&self, tx: &GetChunksTx) -> Result<(), ReplayError> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let listener = listeners
+            .entry(tx.sender)
+            .or_insert_with(|| ListenerNonces::new(tx.nonce));
+        listener.check(tx)?;
+        listener.next = tx.nonce + 1;
+        listener.received.insert(tx.hash);
+        Ok(())
+    }
+
+    /// Forget the nonces of the listener, after one of its transactions did not confirm. The next
+    /// nonce is then looked up on-chain again.
+    pub fn forget(&self, listener: Address) {
+        self.listeners.lock().unwrap().remove(&listener);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        library::{client::TangleTunesClient, crypto::Wallet, util::SongId},
+        test,
+    };
+
+    const CONTRACT_ADDRESS: &str = "0x8fA1fc1Eec824a36fD31497EAa8716Fc9C446d51";
+
+    /// Sign a get-chunks transaction with the next nonce of the listener.
+    async fn signed_tx(
+        distributor: &TangleTunesClient,
+        listener: &TangleTunesClient,
+        index: usize,
+    ) -> GetChunksTx {
+        let song_id = SongId::try_from_hex(test::HEX_ID_1).unwrap();
+        let raw = listener
+            .create_get_chunks_signed_rlp(song_id, index, 10, distributor.wallet_address())
+            .await
+            .unwrap();
+        distributor.validate_get_chunks_tx(raw).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replays_are_refused() -> eyre::Result<()> {
+        let distributor = TangleTunesClient::initialize_offline(
+            Wallet::generate(test::CHAIN_ID),
+            CONTRACT_ADDRESS,
+        )?;
+        let wallet = Wallet::generate(test::CHAIN_ID);
+        let listener = TangleTunesClient::initialize_offline(wallet.clone(), CONTRACT_ADDRESS)?;
+
+        let nonces = NonceTracker::new();
+        nonces.insert(listener.wallet_address(), 0.into());
+
+        // The same transaction is only accepted once.
+        let tx0 = signed_tx(&distributor, &listener, 0).await;
+        nonces.reserve(&tx0)?;
+        assert_eq!(nonces.reserve(&tx0), Err(ReplayError::Duplicate(tx0.hash)));
+
+        // Another transaction with the same nonce is refused.
+        let same_wallet = TangleTunesClient::initialize_offline(wallet, CONTRACT_ADDRESS)?;
+        let reused = signed_tx(&distributor, &same_wallet, 10).await;
+        assert_eq!(reused.nonce, tx0.nonce);
+        assert!(matches!(
+            nonces.reserve(&reused),
+            Err(ReplayError::NonceReused { .. })
+        ));
+
+        // A transaction that skips a nonce is refused, also when the gap is filled later.
+        let tx1 = signed_tx(&distributor, &listener, 10).await;
+        let tx2 = signed_tx(&distributor, &listener, 20).await;
+        assert!(matches!(
+            nonces.check(&tx2),
+            Err(ReplayError::NonceGap { .. })
+        ));
+        assert!(matches!(
+            nonces.reserve(&tx2),
+            Err(ReplayError::NonceGap { .. })
+        ));
+        nonces.reserve(&tx1)?;
+        nonces.reserve(&tx2)?;
+
+        // Forgotten listeners start again at the nonce of their next transaction.
+        nonces.forget(listener.wallet_address());
+        assert!(!nonces.contains(listener.wallet_address()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_the_next_nonce_is_accepted() -> eyre::Result<()> {
+        let distributor = TangleTunesClient::initialize_offline(
+            Wallet::generate(test::CHAIN_ID),
+            CONTRACT_ADDRESS,
+        )?;
+        let listener = TangleTunesClient::initialize_offline(
+            Wallet::generate(test::CHAIN_ID),
+            CONTRACT_ADDRESS,
+        )?;
+        let mut txs = Vec::new();
+        for index in 0..8 {
+            txs.push(signed_tx(&distributor, &listener, index).await);
+        }
+
+        // Nonces below the on-chain transaction-count have been used.
+        let nonces = NonceTracker::new();
+        nonces.insert(listener.wallet_address(), 5.into());
+        assert_eq!(
+            nonces.reserve(&txs[4]),
+            Err(ReplayError::NonceReused {
+                listener: listener.wallet_address(),
+                nonce: 4.into(),
+                expected: 5.into(),
+            })
+        );
+
+        // Any nonce after the next one is a gap, which is not reserved.
+        assert_eq!(
+            nonces.reserve(&txs[6]),
+            Err(ReplayError::NonceGap {
+                listener: listener.wallet_address(),
+                nonce: 6.into(),
+                expected: 5.into(),
+            })
+        );
+        nonces.reserve(&txs[5])?;
+        assert!(matches!(
+            nonces.check(&txs[7]),
+            Err(ReplayError::NonceGap { .. })
+        ));
+
+        // Reserved nonces cannot be used again.
+        assert!(matches!(
+            nonces.check(&txs[5]),
+            Err(ReplayError::Duplicate(_))
+        ));
+        nonces.reserve(&txs[6])?;
+        nonces.reserve(&txs[7])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use super::SharedState;
+use crate::library::{
+    app::App,
+    client::WEI_PER_IOTA,
+    metrics::METRICS,
+    transaction_pool::{TransactionPool, TxFailure},
+    util::SongId,
+};
+use ethers::types::{Address, Bytes, TransactionReceipt, H256, U256};
+use std::time::Duration;
+
+/// A get-chunks transaction of a listener that pays for chunks of a song.
+#[derive(Debug, Clone, Copy)]
+pub struct Payment {
+    pub listener: Address,
+    pub tx_hash: H256,
+    pub song: SongId,
+    pub chunks: u32,
+    /// Our fee for all chunks, in wei.
+    pub fee: U256,
+}
+
+/// Settle a payment of the listener once its transaction has confirmed or failed, and remove it
+/// from the pending payments. Confirmed payments are recorded as earnings. Failures are recorded
+/// as well, and only reverted transactions mark the listener as failed in the ledger. After a
+/// failure the nonce of the listener is looked up again.
+///
+/// Returns the failure of the transaction, if any.
+pub async fn settle_payment(
+    app: &'static App,
+    shared: &SharedState,
+    payment: Payment,
+    result: Result<TransactionReceipt, TxFailure>,
+) -> eyre::Result<Option<TxFailure>> {
+    let Payment {
+        listener, tx_hash, ..
+    } = payment;
+    let ledger = &shared.ledger;
+    let failure = match result {
+        Ok(receipt) => {
+            ledger.confirm(listener, tx_hash).await?;
+            let fee = payment.fee / U256::from(WEI_PER_IOTA);
+            let block_number = receipt.block_number.map(|number| number.as_u64());
+            app.database
+                .add_earning(
+                    tx_hash,
+                    &payment.song,
+                    listener,
+                    payment.chunks,
+                    fee.low_u64(),
+                    block_number,
+                )
+                .await?;
+            METRICS.add_payment("confirmed");
+            None
+        }
+        Err(failure) => {
+            println!("Payment of {listener:?} failed: {failure}");
+            METRICS.add_payment(failure.kind());
+            let message = format!("{failure:#}");
+            app.database
+                .add_payment_failure(listener, tx_hash, failure.kind(), &message)
+                .await?;
+            match failure {
+                TxFailure::Reverted(_) => ledger.fail(listener, tx_hash),
+                _ => ledger.abandon(listener, tx_hash),
+            }
+            shared.nonces.forget(listener);
+            Some(failure)
+        }
+    };
+
+    app.database.remove_pending_payment(tx_hash).await?;
+    Ok(failure)
+}
+
+/// Reconcile the payments that were still pending when the distributor stopped. Transactions that
+/// have been included in a block are settled right away, while the others are sent again. A
+/// payment that cannot be recovered is skipped and stays pending until the next start.
+///
+/// Payments only pay off debt in the ledger if debt is persisted, since the debt of the chunks
+/// they paid for is lost otherwise.
+pub async fn recover_pending_payments(app: &'static App, shared: &SharedState) -> eyre::Result<()> {
+    let payments = app.database.get_pending_payments().await?;
+    if payments.is_empty() {
+        return Ok(());
+    }
+    println!("Recovering {} pending payments..", payments.len());
+
+    let mut transaction_pool = TransactionPool::new(&app.client, Duration::from_millis(100), 7);
+    for (listener, tx_hash, raw, chunks, song_and_fee) in payments {
+        let raw = Bytes::from(raw);
+        let (song, fee) = match song_and_fee {
+            Some(song_and_fee) => song_and_fee,
+            None => match recover_song_and_fee(app, raw.clone(), chunks).await {
+                Ok(song_and_fee) => song_and_fee,
+                Err(e) => {
+                    eprintln!("Recovery of pending payment {tx_hash:?} failed: {e:#}");
+                    continue;
+                }
+            },
+        };
+        let receipt = match app.client.get_transaction_receipt(tx_hash).await {
+            Ok(receipt) => receipt,
+            Err(e) => {
+                eprintln!("Recovery of pending payment {tx_hash:?} failed: {e:#}");
+                continue;
+            }
+        };
+        let payment = Payment {
+            listener,
+            tx_hash,
+            song,
+            chunks,
+            fee,
+        };
+
+        if app.persist_debt {
+            shared.ledger.add_transaction(listener, tx_hash, chunks);
+        }
+        match receipt {
+            Some(receipt) => {
+                let result = match receipt.status {
+                    Some(status) if status.is_zero() => Err(TxFailure::Reverted(tx_hash)),
+                    _ => Ok(receipt),
+                };
+                settle_recovered_payment(app, shared, payment, result).await;
+            }
+            None => transaction_pool.push_raw_tx(raw, payment),
+        }
+    }
+
+    while let Some((result, payment)) = transaction_pool.next().await {
+        settle_recovered_payment(app, shared, payment, result).await;
+    }
+    println!("Recovery of pending payments finished!");
+    Ok(())
+}
+
+/// The song and fee of a payment that was stored by an older version, which did not store them.
+/// The song is recovered from the transaction itself, and the fee is our current fee.
+async fn recover_song_and_fee(
+    app: &'static App,
+    raw: Bytes,
+    chunks: u32,
+) -> eyre::Result<(SongId, U256)> {
+    let tx = app.client.validate_get_chunks_tx(raw)?;
+    let song = SongId::from(tx.params.song);
+    let fee_per_chunk = app
+        .client
+        .get_distribution_fee(song, app.client.wallet_address())
+        .await?;
+    Ok((song, fee_per_chunk.saturating_mul(chunks.into())))
+}
+
+/// Settle a recovered payment, where a failure only skips this payment.
+async fn settle_recovered_payment(
+    app: &'static App,
+    shared: &SharedState,
+    payment: Payment,
+    result: Result<TransactionReceipt, TxFailure>,
+) {
+    if let Err(e) = settle_payment(app, shared, payment, result).await {
+        eprintln!(
+            "Recovery of pending payment {:?} failed: {e:#}",
+            payment.tx_hash
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        command::distribute::{ledger::DebtLedger, test_node::TestNode, DEBT_LIMIT},
+        library::{client::TangleTunesClient, crypto::Wallet},
+        test,
+    };
+    use ethers::utils::keccak256;
+
+    #[tokio::test]
+    async fn payments_are_recovered_with_their_stored_fee() -> eyre::Result<()> {
+        let node = TestNode::start().await?;
+        node.mine();
+        let app = App::init_with_node(&node.url).await?;
+        let shared = SharedState::new(app, DebtLedger::load(DEBT_LIMIT, None).await?);
+        let wallet = Wallet::generate(test::CHAIN_ID);
+        let client = TangleTunesClient::initialize_offline(wallet, &app.contract_address)?;
+        let (listener, song) = (
+            client.wallet_address(),
+            SongId::try_from_hex(test::HEX_ID_1)?,
+        );
+        // Our fee when the chunks were served, which is not the current fee of the test-node.
+        let fee = U256::from(WEI_PER_IOTA) * 50;
+
+        // A payment that cannot be looked up is skipped..
+        let failing = H256::random();
+        node.fail_lookups(failing);
+        app.database
+            .add_pending_payment(listener, failing, &[1, 2, 3], &song, 10, fee)
+            .await?;
+        // .. while the others are still recovered.
+        let raw = client
+            .create_get_chunks_signed_rlp(song, 0, 10, app.client.wallet_address())
+            .await?;
+        let tx_hash = H256::from(keccak256(&raw));
+        app.database
+            .add_pending_payment(listener, tx_hash, &raw, &song, 10, fee)
+            .await?;
+
+        recover_pending_payments(app, &shared).await?;
+        assert_eq!(
+            app.database.get_earnings_per_listener().await?,
+            vec![(listener, 1, 10, 50)]
+        );
+        let pending = app.database.get_pending_payments().await?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].1, failing);
+        Ok(())
+    }
+}
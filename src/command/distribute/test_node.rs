@@ -0,0 +1,182 @@
+use crate::library::{
+    abi::{DistributionsReturn, SongsReturn, TangleTunesAbiCalls, UsersReturn},
+    client::WEI_PER_IOTA,
+};
+use ethers::{
+    abi::{AbiDecode, Tokenize},
+    types::{Bytes, Transaction, TransactionReceipt, H256, U256, U64},
+    utils::keccak256,
+};
+use hyper::{
+    body,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// The fee per chunk of every distribution on the test-node, in wei.
+pub const FEE_PER_CHUNK: u128 = WEI_PER_IOTA;
+
+/// A node that answers the json-rpc requests a distributor makes while serving listeners. All
+/// accounts and songs exist with plenty of balance, and transactions that are sent stay pending
+/// until the node starts mining.
+#[derive(Clone)]
+pub struct TestNode {
+    pub url: String,
+    chain: Arc<Mutex<Chain>>,
+}
+
+#[derive(Default)]
+struct Chain {
+    /// The sent transactions, with the block they were mined in.
+    transactions: HashMap<H256, Option<U64>>,
+    /// Whether sent transactions are mined right away.
+    mining: bool,
+    /// The transactions of which lookups fail.
+    failing: HashSet<H256>,
+    /// The transactions that revert once they are mined.
+    reverting: HashSet<H256>,
+}
+
+impl TestNode {
+    pub async fn start() -> eyre::Result<Self> {
+        let chain = Arc::new(Mutex::new(Chain::default()));
+        let node_chain = chain.clone();
+        let make_service = make_service_fn(move |_| {
+            let chain = node_chain.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let chain = chain.clone();
+                    async move { Ok::<_, Infallible>(respond(request, &chain).await) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::task::spawn(server);
+        Ok(Self { url, chain })
+    }
+
+    /// Include all pending transactions in a block, and mine the ones that are sent from now on
+    /// right away.
+    pub fn mine(&self) {
+        let mut chain = self.chain.lock().unwrap();
+        chain.mining = true;
+        for block in chain.transactions.values_mut() {
+            block.get_or_insert(1.into());
+        }
+    }
+
+    /// Revert the transaction once it is mined.
+    pub fn revert(&self, hash: H256) {
+        self.chain.lock().unwrap().reverting.insert(hash);
+    }
+
+    /// Fail all lookups of the transaction, like a node that is having problems.
+    pub fn fail_lookups(&self, hash: H256) {
+        self.chain.lock().unwrap().failing.insert(hash);
+    }
+}
+
+async fn respond(request: Request<Body>, chain: &Mutex<Chain>) -> Response<Body> {
+    let request: Value = match body::to_bytes(request.into_body()).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        Err(_) => Value::Null,
+    };
+    let params = &request["params"];
+    let result = match request["method"].as_str().unwrap_or_default() {
+        "eth_chainId" => Ok(json!(U64::from(crate::test::CHAIN_ID))),
+        "eth_blockNumber" => Ok(json!(U64::from(1))),
+        "eth_getTransactionCount" => Ok(json!(U256::zero())),
+        "eth_getBalance" => Ok(json!(U256::from(WEI_PER_IOTA) * 1_000_000)),
+        "eth_call" => call(&params[0]["data"]),
+        "eth_sendRawTransaction" => {
+            let raw: Bytes = serde_json::from_value(params[0].clone()).unwrap_or_default();
+            let hash = H256::from(keccak256(&raw));
+            let mut chain = chain.lock().unwrap();
+            let block_number = chain.mining.then_some(1.into());
+            chain.transactions.insert(hash, block_number);
+            Ok(json!(hash))
+        }
+        "eth_getTransactionByHash" | "eth_getTransactionReceipt"
+            if chain.lock().unwrap().failing.contains(&tx_hash(params)) =>
+        {
+            Err("Transaction lookup failed".to_string())
+        }
+        "eth_getTransactionByHash" => {
+            let hash = tx_hash(params);
+            Ok(match chain.lock().unwrap().transactions.get(&hash) {
+                Some(block_number) => json!(Transaction {
+                    hash,
+                    block_number: *block_number,
+                    ..Default::default()
+                }),
+                None => Value::Null,
+            })
+        }
+        "eth_getTransactionReceipt" => {
+            let hash = tx_hash(params);
+            let chain = chain.lock().unwrap();
+            Ok(match chain.transactions.get(&hash) {
+                Some(Some(block_number)) => json!(TransactionReceipt {
+                    transaction_hash: hash,
+                    block_number: Some(*block_number),
+                    status: Some((!chain.reverting.contains(&hash) as u64).into()),
+                    ..Default::default()
+                }),
+                _ => Value::Null,
+            })
+        }
+        method => Err(format!("Method {method} is not supported")),
+    };
+
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+        Err(message) => json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "error": { "code": -32601, "message": message },
+        }),
+    };
+    Response::new(Body::from(response.to_string()))
+}
+
+/// The transaction-hash that is the first parameter of a request.
+fn tx_hash(params: &Value) -> H256 {
+    serde_json::from_value(params[0].clone()).unwrap_or_default()
+}
+
+/// Execute a call to the smart-contract.
+fn call(data: &Value) -> Result<Value, String> {
+    let data: Bytes = serde_json::from_value(data.clone()).map_err(|e| e.to_string())?;
+    let tokens = match TangleTunesAbiCalls::decode(&data).map_err(|e| e.to_string())? {
+        TangleTunesAbiCalls::Songs(_) => SongsReturn {
+            exists: true,
+            price: WEI_PER_IOTA.into(),
+            ..Default::default()
+        }
+        .into_tokens(),
+        TangleTunesAbiCalls::Users(_) => UsersReturn {
+            exists: true,
+            balance: U256::from(WEI_PER_IOTA) * 1_000_000,
+            ..Default::default()
+        }
+        .into_tokens(),
+        TangleTunesAbiCalls::GenDistributionId(_) => [0_u8; 32].into_tokens(),
+        TangleTunesAbiCalls::Distributions(_) => DistributionsReturn {
+            fee: FEE_PER_CHUNK.into(),
+            ..Default::default()
+        }
+        .into_tokens(),
+        TangleTunesAbiCalls::GetChunks(_) => Vec::new(),
+        call => return Err(format!("Call {call:?} is not supported")),
+    };
+    Ok(json!(Bytes::from(ethers::abi::encode(&tokens))))
+}
@@ -0,0 +1,55 @@
+use crate::library::tcp::{ControlFrame, ErrorCode, Features, SendChunksEncoder};
+use eyre::Context;
+use futures::SinkExt;
+use std::{fmt::Display, net::SocketAddr, time::Duration};
+use tokio::io::AsyncWrite;
+use tokio_util::codec::{Encoder, FramedWrite};
+
+/// The sending half of the connection with a listener, where every frame must be written within
+/// the write-timeout.
+pub struct ListenerWriter<W> {
+    inner: FramedWrite<W, SendChunksEncoder>,
+    addr: SocketAddr,
+    /// The features of the listener, which are known after the handshake.
+    pub features: Features,
+    write_timeout: Duration,
+}
+
+impl<W: AsyncWrite + Unpin> ListenerWriter<W> {
+    pub fn new(writer: W, addr: SocketAddr, write_timeout: Duration) -> Self {
+        Self {
+            inner: FramedWrite::new(writer, SendChunksEncoder),
+            addr,
+            features: Features::LEGACY,
+            write_timeout,
+        }
+    }
+
+    /// Send a frame to the listener.
+    pub async fn send<I>(&mut self, item: I) -> eyre::Result<()>
+    where
+        SendChunksEncoder: Encoder<I, Error = eyre::Error>,
+    {
+        match tokio::time::timeout(self.write_timeout, self.inner.send(item)).await {
+            Ok(result) => result,
+            Err(_) => bail!(
+                "Writing to {} timed out after {:?}",
+                self.addr,
+                self.write_timeout
+            ),
+        }
+    }
+
+    /// Refuse the request of the listener by sending an error-frame, after which the connection
+    /// should be closed. Listeners that do not support error-frames are only refused in the logs.
+    pub async fn refuse(&mut self, code: ErrorCode, message: impl Display) -> eyre::Result<()> {
+        println!("Refusing get-chunks from {}: {message}", self.addr);
+        if !self.features.contains(Features::ERROR_FRAMES) {
+            return Ok(());
+        }
+        let addr = self.addr;
+        self.send(&ControlFrame::error(code, message))
+            .await
+            .wrap_err(format!("Could not send error-frame to {addr}"))
+    }
+}
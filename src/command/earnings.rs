@@ -0,0 +1,21 @@
+use super::report::{print_report, to_rows};
+use crate::{arguments::EarningsBy, library::app::App};
+
+/// Print the earnings from confirmed get-chunks transactions, grouped by song, listener or day.
+pub async fn report(app: &'static App, by: EarningsBy) -> eyre::Result<()> {
+    let (title, rows) = match by {
+        EarningsBy::Song => ("song", to_rows(app.database.get_earnings_per_song().await?)),
+        EarningsBy::Listener => {
+            // Addresses are displayed abbreviated, so they are printed in full with debug.
+            let rows = app.database.get_earnings_per_listener().await?;
+            let rows = rows.into_iter().map(|(listener, payments, chunks, fee)| {
+                (format!("{listener:?}"), payments, chunks, fee)
+            });
+            ("listener", to_rows(rows.collect()))
+        }
+        EarningsBy::Day => ("day", to_rows(app.database.get_earnings_per_day().await?)),
+    };
+
+    print_report(&format!("Earnings per {title}"), rows);
+    Ok(())
+}
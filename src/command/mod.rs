@@ -1,5 +1,9 @@
 pub mod account;
+pub mod ctl;
 pub mod distribute;
+pub mod earnings;
+mod report;
 pub mod song_index;
 pub mod songs;
+pub mod spending;
 pub mod wallet;
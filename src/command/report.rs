@@ -0,0 +1,27 @@
+use std::fmt::Display;
+
+/// A row of a report: the key it is grouped by, the amount of payments, the chunks and the fee in
+/// IOTA.
+pub type ReportRow = (String, u32, u64, u64);
+
+/// Convert rows from the database to report-rows by displaying their keys.
+pub fn to_rows<K: Display>(rows: Vec<(K, u32, u64, u64)>) -> Vec<ReportRow> {
+    rows.into_iter()
+        .map(|(key, payments, chunks, fee)| (key.to_string(), payments, chunks, fee))
+        .collect()
+}
+
+/// Print the rows of payments under a title such as `Earnings per song`, followed by the total.
+pub fn print_report(title: &str, rows: Vec<ReportRow>) {
+    println!("-- {title} --");
+    if rows.is_empty() {
+        println!("| no payments yet");
+    }
+    let (mut total_chunks, mut total_fee) = (0, 0);
+    for (key, payments, chunks, fee) in rows {
+        println!("| {key}: {fee} IOTA for {chunks} chunks in {payments} payments");
+        total_chunks += chunks;
+        total_fee += fee;
+    }
+    println!("| total: {total_fee} IOTA for {total_chunks} chunks");
+}
@@ -53,7 +53,7 @@ pub async fn download(
             mapped_indexes
         }
         (Some(amount), None) => {
-            let downloaded_song_ids = app.database.get_all_downloaded_song_ids().await?;
+            let downloaded_song_ids = app.storage.list().await?;
             app.database
                 .get_song_index()
                 .await?
@@ -1,4 +1,7 @@
-use crate::{library::app::App, library::util::SongId, BYTES_PER_CHUNK_USIZE};
+use crate::{
+    library::{app::App, tcp::ServerAddress, util::SongId},
+    BYTES_PER_CHUNK_USIZE,
+};
 use ethers::types::{Address, H160, U256};
 use eyre::Context;
 use num_integer::div_ceil;
@@ -21,7 +24,7 @@ pub async fn remove(ids: Vec<String>, cfg: &'static App) -> eyre::Result<()> {
                 .ok_or_else(|| eyre!("Song-index not found"))?,
         };
 
-        if cfg.database.remove_song(&song_id).await? {
+        if cfg.storage.delete(&song_id).await? {
             println!("Succesfully removed song {id:?}");
         } else {
             println!("Song with id {id:?} does not exist, cannot be removed");
@@ -43,8 +46,8 @@ pub async fn add(paths: Vec<String>, cfg: &'static App) -> eyre::Result<()> {
         }
         let data = std::fs::read(&path)?;
         let song_id = path.file_stem().unwrap().to_str().unwrap();
-        cfg.database
-            .add_song(&SongId::try_from_hex(song_id)?, &data)
+        cfg.storage
+            .put(&SongId::try_from_hex(song_id)?, &data)
             .await?;
         println!("Added song with id {}", song_id);
     }
@@ -53,7 +56,7 @@ pub async fn add(paths: Vec<String>, cfg: &'static App) -> eyre::Result<()> {
 
 pub(crate) async fn run_list(app: &'static App) -> eyre::Result<()> {
     println!("Songs stored locally:");
-    for song_id in app.database.get_all_downloaded_song_ids().await? {
+    for song_id in app.storage.list().await? {
         let index = match app.database.get_index_by_song_id(&song_id).await? {
             Some(index) => format!("index: {index}"),
             None => "index not found".to_string(),
@@ -63,6 +66,28 @@ pub(crate) async fn run_list(app: &'static App) -> eyre::Result<()> {
     Ok(())
 }
 
+/// Check the chunks of all stored songs against their hashes. Songs with corrupt chunks must be
+/// removed and added again.
+pub async fn verify(app: &'static App) -> eyre::Result<()> {
+    let song_ids = app.storage.list().await?;
+    println!("Verifying {} songs..", song_ids.len());
+
+    let mut corrupt_songs = 0;
+    for song_id in &song_ids {
+        let corrupt_chunks = app.storage.corrupt_chunks(song_id).await?;
+        if !corrupt_chunks.is_empty() {
+            corrupt_songs += 1;
+            println!("{song_id} - corrupt chunks: {corrupt_chunks:?}");
+        }
+    }
+
+    if corrupt_songs > 0 {
+        bail!("{corrupt_songs} of {} songs are corrupt", song_ids.len());
+    }
+    println!("All songs are intact");
+    Ok(())
+}
+
 pub async fn download(
     app: &'static App,
     song_id: String,
@@ -101,24 +126,32 @@ pub async fn download(
     let song = app
         .client
         .download_from_distributor(
-            distribution.server.parse()?,
+            distribution.server.parse::<ServerAddress>()?,
             song_id,
             0,
             div_ceil(song_info.len.as_usize(), BYTES_PER_CHUNK_USIZE),
             distribution.distributor,
+            app.allow_unproven_distributors,
+            app.allow_legacy_distributors,
+            app.database,
+            app.limits.max_response_len,
         )
         .await?;
 
     match to_file {
         Some(to_file) => {
-            let mut file = OpenOptions::new().write(true).create(true).open(&to_file)?;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&to_file)?;
             file.write_all(&song)?;
             file.flush()?;
             println!("Wrote mp3 to {}", to_file)
         }
         None => {
-            app.database.add_song(&song_id, &song).await?;
-            println!("Succesfully added song {song_id} to the database");
+            app.storage.put(&song_id, &song).await?;
+            println!("Succesfully stored song {song_id}");
         }
     }
 
@@ -142,10 +175,18 @@ pub async fn download_direct(
             first_chunk_id,
             chunks_requested,
             distributor_address.parse()?,
+            app.allow_unproven_distributors,
+            app.allow_legacy_distributors,
+            app.database,
+            app.limits.max_response_len,
         )
         .await?;
 
-    let mut file = OpenOptions::new().write(true).create(true).open(file)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(file)?;
     file.write_all(&song)?;
     file.flush()?;
 
@@ -0,0 +1,28 @@
+use super::report::{print_report, to_rows};
+use crate::{arguments::SpendingBy, library::app::App};
+
+/// Print the spending on get-chunks transactions signed for downloads, grouped by song,
+/// distributor, day or status.
+pub async fn report(app: &'static App, by: SpendingBy) -> eyre::Result<()> {
+    let (title, rows) = match by {
+        SpendingBy::Song => ("song", to_rows(app.database.get_spending_per_song().await?)),
+        SpendingBy::Distributor => {
+            // Addresses are displayed abbreviated, so they are printed in full with debug.
+            let rows = app.database.get_spending_per_distributor().await?;
+            let rows = rows
+                .into_iter()
+                .map(|(distributor, payments, chunks, fee)| {
+                    (format!("{distributor:?}"), payments, chunks, fee)
+                });
+            ("distributor", to_rows(rows.collect()))
+        }
+        SpendingBy::Day => ("day", to_rows(app.database.get_spending_per_day().await?)),
+        SpendingBy::Status => (
+            "status",
+            to_rows(app.database.get_spending_per_status().await?),
+        ),
+    };
+
+    print_report(&format!("Spending per {title}"), rows);
+    Ok(())
+}
@@ -1,9 +1,17 @@
 use std::path::PathBuf;
 
-use crate::library::app::AppDataBuilder;
+use crate::library::{
+    app::{AdminSettings, AppDataBuilder},
+    limits::ConnectionsConfig,
+    storage::StorageSettings,
+    tls::TlsPaths,
+};
 use eyre::Context;
 use serde::{Deserialize, Serialize};
 
+/// The bytes of chunks that are cached in memory while distributing, if not configured.
+const DEFAULT_CHUNK_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigFile {
     pub contract_address: String,
@@ -14,19 +22,80 @@ pub struct ConfigFile {
     pub server_address: String,
     pub bind_address: String,
     pub max_price: Option<u64>,
+    pub persist_debt: Option<bool>,
+    pub allow_unproven_distributors: Option<bool>,
+    pub allow_legacy_distributors: Option<bool>,
+    pub metrics_address: Option<String>,
+    pub chunk_cache_size: Option<usize>,
+    pub control_socket: Option<String>,
+    pub tls: Option<TlsConfig>,
+    pub admin: Option<AdminConfig>,
+    pub connections: Option<ConnectionsConfig>,
+    pub storage: Option<StorageConfig>,
+}
+
+/// The `[tls]` table, with paths relative to the config-file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// The `[storage]` table, with the directory relative to the config-file.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Sqlite,
+    Filesystem { directory: String },
+}
+
+/// The `[admin]` table, for the admin-api that is served while distributing.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminConfig {
+    pub address: String,
+    pub token: String,
+}
+
+/// The path relative to the directory of the config-file.
+fn relative_to_config(config_path: &str, path: &str) -> PathBuf {
+    let mut full_path = PathBuf::from(config_path);
+    full_path.pop();
+    full_path.push(path);
+    full_path
 }
 
 impl ConfigFile {
+    /// The path of the unix-socket with which a running distributor is controlled, which is
+    /// next to the database by default.
+    pub fn control_socket_path(&self, config_path: &str) -> PathBuf {
+        match &self.control_socket {
+            Some(path) => relative_to_config(config_path, path),
+            None => relative_to_config(config_path, &format!("{}.sock", self.database_path)),
+        }
+    }
+
+    /// The path of the log-file of a distributor that runs as daemon, next to the database.
+    pub fn daemon_log_path(&self, config_path: &str) -> PathBuf {
+        relative_to_config(config_path, &format!("{}.log", self.database_path))
+    }
+
     pub fn parse_to_app_builder(
         self,
         password: Option<String>,
         config_path: &str,
     ) -> eyre::Result<AppDataBuilder> {
-        let database_path = {
-            let mut database_path = PathBuf::from(config_path);
-            database_path.pop();
-            database_path.push(&self.database_path);
-            database_path
+        let control_socket = self.control_socket_path(config_path);
+        let relative_to_config = |path: &str| relative_to_config(config_path, path);
+        let database_path = relative_to_config(&self.database_path);
+        let tls = self.tls.map(|tls| TlsPaths {
+            cert_path: relative_to_config(&tls.cert_path),
+            key_path: relative_to_config(&tls.key_path),
+        });
+        let storage = match self.storage {
+            None | Some(StorageConfig::Sqlite) => StorageSettings::Sqlite,
+            Some(StorageConfig::Filesystem { directory }) => StorageSettings::Filesystem {
+                directory: relative_to_config(&directory),
+            },
         };
 
         Ok(AppDataBuilder {
@@ -39,6 +108,31 @@ impl ConfigFile {
             server_address: self.server_address.parse()?,
             bind_address: self.bind_address.parse()?,
             max_price_iota: self.max_price,
+            persist_debt: self.persist_debt.unwrap_or(false),
+            allow_unproven_distributors: self.allow_unproven_distributors.unwrap_or(false),
+            allow_legacy_distributors: self.allow_legacy_distributors.unwrap_or(true),
+            metrics_address: self
+                .metrics_address
+                .map(|address| address.parse())
+                .transpose()?,
+            chunk_cache_size: self.chunk_cache_size.unwrap_or(DEFAULT_CHUNK_CACHE_SIZE),
+            control_socket,
+            admin: self
+                .admin
+                .map(|admin| -> eyre::Result<_> {
+                    // An empty token would authorize every request with an empty bearer-token.
+                    if admin.token.trim().is_empty() {
+                        bail!("The token of the admin-api must not be empty");
+                    }
+                    Ok(AdminSettings {
+                        address: admin.address.parse()?,
+                        token: admin.token,
+                    })
+                })
+                .transpose()?,
+            tls,
+            storage,
+            limits: self.connections.unwrap_or_default().into(),
         })
     }
 
@@ -4,8 +4,16 @@ use crate::library::{
     client::TangleTunesClient,
     crypto::{self, Wallet},
     database::Database,
+    limits::ConnectionLimits,
+    storage::{SongStorage, StorageSettings},
+    tls::TlsPaths,
+};
+use std::{
+    fmt::Debug,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
 };
-use std::{fmt::Debug, net::SocketAddr, path::PathBuf};
 
 use super::client::WEI_PER_IOTA;
 
@@ -18,15 +26,46 @@ pub struct App {
     pub node_url: String,
     pub database_path: PathBuf,
     pub chain_id: u16,
-    pub fee: U256,
+    /// Our fee per chunk in IOTA, which can be changed while distributing.
+    fee: AtomicU32,
     pub database: Database,
     pub client: TangleTunesClient,
+    pub wallet: Wallet,
     pub server_address: SocketAddr,
     pub bind_address: SocketAddr,
     pub max_price_wei: U256,
+    pub persist_debt: bool,
+    pub allow_unproven_distributors: bool,
+    pub allow_legacy_distributors: bool,
+    pub metrics_address: Option<SocketAddr>,
+    /// The bytes of chunks that are cached in memory while distributing.
+    pub chunk_cache_size: usize,
+    pub control_socket: PathBuf,
+    pub admin: Option<AdminSettings>,
+    pub tls: Option<TlsPaths>,
+    pub limits: ConnectionLimits,
+    /// Where the mp3-data of the songs is stored.
+    pub storage: Box<dyn SongStorage>,
+}
+
+/// The address and bearer-token of the admin-api.
+#[derive(Debug, Clone)]
+pub struct AdminSettings {
+    pub address: SocketAddr,
+    pub token: String,
 }
 
 impl App {
+    /// Our fee per chunk in IOTA.
+    pub fn fee(&self) -> U256 {
+        self.fee.load(Ordering::Relaxed).into()
+    }
+
+    /// Change our fee per chunk in IOTA, for songs that are registered from now on.
+    pub fn set_fee(&self, fee: u32) {
+        self.fee.store(fee, Ordering::Relaxed);
+    }
+
     /// Updates the internal song-list with data from the smart contract.
     pub async fn update_song_list(&self) -> eyre::Result<()> {
         let index = self.database.get_next_song_index().await?;
@@ -59,6 +98,43 @@ impl App {
 
         AppDataBuilder::_build(builder, in_memory).await
     }
+
+    /// An app with a new wallet and an in-memory database, which uses the node at the url.
+    #[cfg(test)]
+    pub async fn init_with_node(node_url: &str) -> eyre::Result<&'static App> {
+        const CONTRACT_ADDRESS: &str = "0x8fA1fc1Eec824a36fD31497EAa8716Fc9C446d51";
+        let chain_id = crate::test::CHAIN_ID;
+        let wallet = Wallet::generate(chain_id);
+        let database = Database::initialize_in_memory().await?;
+        let client =
+            TangleTunesClient::initialize(wallet.clone(), node_url, CONTRACT_ADDRESS).await?;
+
+        let app = App {
+            password: None,
+            contract_address: CONTRACT_ADDRESS.to_string(),
+            node_url: node_url.to_string(),
+            database,
+            database_path: PathBuf::from(":memory:"),
+            chain_id,
+            fee: AtomicU32::new(1),
+            client,
+            wallet,
+            server_address: "127.0.0.1:0".parse()?,
+            bind_address: "127.0.0.1:0".parse()?,
+            max_price_wei: DEFAULT_MAX_PRICE.into(),
+            persist_debt: false,
+            allow_unproven_distributors: false,
+            allow_legacy_distributors: false,
+            metrics_address: None,
+            chunk_cache_size: 0,
+            control_socket: std::env::temp_dir().join("tangle-tunes-test.sock"),
+            admin: None,
+            tls: None,
+            limits: ConnectionLimits::default(),
+            storage: StorageSettings::Sqlite.open(database)?,
+        };
+        Ok(Box::leak(Box::new(app)))
+    }
 }
 
 pub struct AppDataBuilder {
@@ -71,6 +147,17 @@ pub struct AppDataBuilder {
     pub server_address: SocketAddr,
     pub bind_address: SocketAddr,
     pub max_price_iota: Option<u64>,
+    pub persist_debt: bool,
+    pub allow_unproven_distributors: bool,
+    pub allow_legacy_distributors: bool,
+    pub metrics_address: Option<SocketAddr>,
+    /// The bytes of chunks that are cached in memory while distributing.
+    pub chunk_cache_size: usize,
+    pub control_socket: PathBuf,
+    pub admin: Option<AdminSettings>,
+    pub tls: Option<TlsPaths>,
+    pub limits: ConnectionLimits,
+    pub storage: StorageSettings,
 }
 
 impl AppDataBuilder {
@@ -100,7 +187,8 @@ impl AppDataBuilder {
         }?;
 
         let client =
-            TangleTunesClient::initialize(wallet, &self.node_url, &self.contract_address).await?;
+            TangleTunesClient::initialize(wallet.clone(), &self.node_url, &self.contract_address)
+                .await?;
 
         let max_price_wei = match self.max_price_iota {
             Some(max_price) => ((max_price as u128) * WEI_PER_IOTA).into(),
@@ -114,11 +202,22 @@ impl AppDataBuilder {
             database,
             database_path: PathBuf::from(self.database_path),
             chain_id: self.chain_id,
-            fee: self.fee.into(),
+            fee: AtomicU32::new(self.fee),
             client,
+            wallet,
             server_address: self.server_address,
             bind_address: self.bind_address,
             max_price_wei,
+            persist_debt: self.persist_debt,
+            allow_unproven_distributors: self.allow_unproven_distributors,
+            allow_legacy_distributors: self.allow_legacy_distributors,
+            metrics_address: self.metrics_address,
+            chunk_cache_size: self.chunk_cache_size,
+            control_socket: self.control_socket,
+            admin: self.admin,
+            tls: self.tls,
+            limits: self.limits,
+            storage: self.storage.open(database)?,
         };
 
         Ok(Box::leak(Box::new(app)))
@@ -0,0 +1,219 @@
+use crate::{
+    library::{metrics::METRICS, storage::SongStorage, util::SongId},
+    BYTES_PER_CHUNK,
+};
+use bytes::Bytes;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+/// An in-memory cache of the chunks of songs that were served recently, so that popular songs
+/// are not read from the storage for every request. It holds at most `capacity` bytes, and
+/// evicts the chunks that were used least recently.
+#[derive(Debug)]
+pub struct ChunkCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+/// The chunks by song and index, together with the order in which they were used.
+#[derive(Debug, Default)]
+struct Lru {
+    /// The cached chunks, with the tick at which they were used last.
+    chunks: HashMap<(SongId, u32), (Bytes, u64)>,
+    /// The cached chunks by the tick at which they were used last, oldest first.
+    usage: BTreeMap<u64, (SongId, u32)>,
+    /// Incremented whenever a chunk is used.
+    tick: u64,
+    /// The total bytes of the cached chunks.
+    size: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &(SongId, u32)) -> Option<Bytes> {
+        let (chunk, used) = self.chunks.get_mut(key)?;
+        self.usage.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.usage.insert(self.tick, *key);
+        Some(chunk.clone())
+    }
+
+    /// Insert the chunk, and evict the least recently used chunks until it fits.
+    fn insert(&mut self, key: (SongId, u32), chunk: Bytes, capacity: usize) {
+        if chunk.len() > capacity {
+            return;
+        }
+        if let Some((old, used)) = self.chunks.remove(&key) {
+            self.usage.remove(&used);
+            self.size -= old.len();
+        }
+        while self.size + chunk.len() > capacity {
+            let Some((_, evicted)) = self.usage.pop_first() else {
+                break;
+            };
+            let (old, _) = self.chunks.remove(&evicted).unwrap();
+            self.size -= old.len();
+        }
+
+        self.tick += 1;
+        self.size += chunk.len();
+        self.usage.insert(self.tick, key);
+        self.chunks.insert(key, (chunk, self.tick));
+    }
+
+    fn remove_song(&mut self, song: &SongId) {
+        self.chunks.retain(|(id, _), _| id != song);
+        self.usage.retain(|_, (id, _)| id != song);
+        self.size = self.chunks.values().map(|(chunk, _)| chunk.len()).sum();
+    }
+}
+
+impl ChunkCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// Get the chunks from (index, index + amount), from the cache where possible. Only the range
+    /// from the first to the last chunk that is not cached is read from the storage, after which
+    /// those chunks are cached as well.
+    ///
+    /// Songs can be removed from the storage by another process, such as the `songs remove`
+    /// command, so cached chunks are only served while the storage still holds them. Otherwise
+    /// the chunks of the song are removed from the cache.
+    pub async fn get_chunks(
+        &self,
+        storage: &dyn SongStorage,
+        song: &SongId,
+        index: u32,
+        amount: u32,
+    ) -> eyre::Result<Vec<u8>> {
+        let mut cached: Vec<Option<Bytes>> = {
+            let mut lru = self.lru.lock().unwrap();
+            (index..index + amount)
+                .map(|i| lru.get(&(*song, i)))
+                .collect()
+        };
+        let mut hits = cached.iter().flatten().count() as u64;
+        if hits > 0 {
+            let stored = storage.chunk_count(song).await?;
+            if !matches!(stored, Some(chunks) if index + amount <= chunks) {
+                self.remove_song(song);
+                cached = vec![None; amount as usize];
+                hits = 0;
+            }
+        }
+        METRICS.add_chunk_cache_lookups(hits, amount as u64 - hits);
+
+        let missing = match cached.iter().position(Option::is_none) {
+            Some(first) => {
+                let last = cached.iter().rposition(Option::is_none).unwrap();
+                let bytes = storage
+                    .get_chunks(song, index + first as u32, (last - first + 1) as u32)
+                    .await?;
+                Some((first, Bytes::from(bytes)))
+            }
+            None => None,
+        };
+
+        let mut lru = self.lru.lock().unwrap();
+        let mut chunks = Vec::with_capacity(amount as usize * BYTES_PER_CHUNK as usize);
+        for (i, chunk) in cached.into_iter().enumerate() {
+            let chunk = match (chunk, &missing) {
+                (Some(chunk), _) => chunk,
+                (None, Some((first, bytes))) => {
+                    // The last chunk of a song can be shorter than the others.
+                    let start = Ord::min((i - first) * BYTES_PER_CHUNK as usize, bytes.len());
+                    let end = Ord::min(start + BYTES_PER_CHUNK as usize, bytes.len());
+                    let chunk = bytes.slice(start..end);
+                    if !chunk.is_empty() {
+                        lru.insert((*song, index + i as u32), chunk.clone(), self.capacity);
+                    }
+                    chunk
+                }
+                (None, None) => unreachable!(),
+            };
+            chunks.extend_from_slice(&chunk);
+        }
+        METRICS.set_chunk_cache_bytes(lru.size);
+        Ok(chunks)
+    }
+
+    /// Remove all chunks of the song, which must be done when it is removed from the storage.
+    pub fn remove_song(&self, song: &SongId) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove_song(song);
+        METRICS.set_chunk_cache_bytes(lru.size);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        library::{database::Database, storage::SqliteStorage},
+        test,
+    };
+
+    fn chunk(byte: u8) -> Bytes {
+        Bytes::from(vec![byte; 10])
+    }
+
+    #[test]
+    fn least_recently_used_chunks_are_evicted() -> eyre::Result<()> {
+        let song = SongId::try_from_hex(test::HEX_ID_1)?;
+        let mut lru = Lru::default();
+
+        lru.insert((song, 0), chunk(0), 30);
+        lru.insert((song, 1), chunk(1), 30);
+        lru.insert((song, 2), chunk(2), 30);
+        assert_eq!(lru.size, 30);
+
+        // Chunk 0 is used, so chunk 1 is evicted first
+        assert_eq!(lru.get(&(song, 0)), Some(chunk(0)));
+        lru.insert((song, 3), chunk(3), 30);
+        assert_eq!(lru.get(&(song, 1)), None);
+        assert_eq!(lru.get(&(song, 0)), Some(chunk(0)));
+        assert_eq!(lru.size, 30);
+
+        // Chunks larger than the capacity are never cached
+        lru.insert((song, 4), Bytes::from(vec![4; 31]), 30);
+        assert_eq!(lru.get(&(song, 4)), None);
+
+        lru.remove_song(&song);
+        assert_eq!(lru.get(&(song, 0)), None);
+        assert_eq!(lru.size, 0);
+        assert!(lru.usage.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunks_are_served_from_cache() -> eyre::Result<()> {
+        let song = SongId::try_from_hex(test::HEX_ID_1)?;
+        let db = Database::initialize_in_memory().await?;
+        let data = std::fs::read(format!("mp3/{}.mp3", test::HEX_ID_1))?;
+        db.add_song(&song, &data).await?;
+        let storage = SqliteStorage::new(db);
+        let cache = ChunkCache::new(10 * BYTES_PER_CHUNK as usize);
+
+        let expected = db.get_chunks(&song, 2, 5).await?;
+        assert_eq!(cache.get_chunks(&storage, &song, 2, 5).await?, expected);
+        assert_eq!(cache.lru.lock().unwrap().chunks.len(), 5);
+        let chunk_size = BYTES_PER_CHUNK as usize;
+        assert_eq!(
+            cache.get_chunks(&storage, &song, 3, 2).await?,
+            expected[chunk_size..3 * chunk_size]
+        );
+
+        // Once the song is gone from the storage, like after removing it in another process, the
+        // cached chunks are not served anymore
+        db.remove_song(&song).await?;
+        assert!(cache.get_chunks(&storage, &song, 3, 2).await.is_err());
+        assert!(cache.lru.lock().unwrap().chunks.is_empty());
+        Ok(())
+    }
+}
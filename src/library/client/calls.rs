@@ -43,6 +43,25 @@ impl TangleTunesClient {
         Ok(self.abi_client.users(address).set_defaults().await?.into())
     }
 
+    /// Get the fee per chunk of the distributor for the given song, in wei.
+    pub async fn get_distribution_fee(
+        &self,
+        song_id: SongId,
+        distributor: Address,
+    ) -> Result<U256, TTCallError> {
+        let distribution_id = self
+            .abi_client
+            .gen_distribution_id(song_id.into(), distributor)
+            .set_defaults()
+            .await?;
+        let (fee, _next_distributor) = self
+            .abi_client
+            .distributions(distribution_id)
+            .set_defaults()
+            .await?;
+        Ok(fee)
+    }
+
     pub async fn call_song_list_length(&self) -> Result<U256, TTCallError> {
         self.abi_client.song_list_length().set_defaults().await
     }
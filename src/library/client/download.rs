@@ -1,23 +1,45 @@
 use crate::{
     library::{
-        client::TangleTunesClient,
-        tcp::{RequestChunksEncoder, SendChunksDecoder},
+        client::{TangleTunesClient, WEI_PER_IOTA},
+        crypto,
+        database::Database,
+        tcp::{
+            Connection, ControlFrame, ErrorCode, Features, FrameError, RequestChunksEncoder,
+            SendChunksDecoder, SendChunksFrame, ServerAddress, MAX_CHUNKS_PER_FRAME,
+            PROTOCOL_VERSION,
+        },
+        tls,
+        transaction_pool::CONFIRMATION_TIMEOUT,
         util::SongId,
     },
     BYTES_PER_CHUNK_USIZE,
 };
-use bytes::BytesMut;
-use ethers::{types::Address, utils::keccak256};
+use ethers::{
+    types::{transaction::eip2718::TypedTransaction, Address, Bytes, H256, U256},
+    utils::{keccak256, rlp::Rlp},
+};
 use ethers_providers::StreamExt;
+use eyre::Context;
 use futures::{SinkExt, Stream};
 use num_integer::div_ceil;
-use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use tokio::{
+    io::{ReadHalf, WriteHalf},
+    net::TcpStream,
+    time::Instant,
+};
 use tokio_util::codec::{FramedRead, FramedWrite};
 
-const CHUNKS_PER_REQUEST: usize = 10;
+const CHUNKS_PER_REQUEST: usize = MAX_CHUNKS_PER_FRAME as usize;
 const CONCURRENT_REQUESTS: usize = 2;
 
+/// The distributor refused our request with an error-frame.
+#[derive(Debug, thiserror::Error)]
+#[error("Distributor refused the request ({code:?}): {message}")]
+pub struct RefusedError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
 impl TangleTunesClient {
     /// Downloads the chunks from the smart-contract and verifies them against the given song-data.
     pub async fn verify_chunks_against_smart_contract(
@@ -50,57 +72,173 @@ impl TangleTunesClient {
         }
     }
 
-    /// Download chunks from the distributor
+    /// Download chunks from the distributor.
+    ///
+    /// Before any paid request is sent, the distributor must prove that it holds the key of
+    /// `distributor_address`, unless `allow_unproven` is set. Distributors that speak the legacy
+    /// protocol cannot prove this, and are only used if `allow_legacy` is set. Every get-chunks
+    /// transaction that is
+    /// signed is recorded in the spending-table of the database, and once the download finished
+    /// we wait until the transactions are mined to record their on-chain status. Frames of the
+    /// distributor with a body longer than `max_response_len` bytes fail the download.
+    #[allow(clippy::too_many_arguments)]
     pub async fn download_from_distributor(
         &'static self,
-        socket_address: SocketAddr,
+        server_address: ServerAddress,
         song_id: SongId,
         first_chunk_id: usize,
         chunk_amount: usize,
         distributor_address: Address,
+        allow_unproven: bool,
+        allow_legacy: bool,
+        database: Database,
+        max_response_len: u32,
     ) -> eyre::Result<Vec<u8>> {
         let last_chunk_id = first_chunk_id + chunk_amount;
 
-        let mut stream = TcpStream::connect(socket_address).await?;
-        let (read_stream, write_stream) = stream.split();
-        let mut read_stream = FramedRead::new(read_stream, SendChunksDecoder::new());
-        let mut write_stream = FramedWrite::new(write_stream, RequestChunksEncoder);
+        let mut connection =
+            DistributorConnection::connect(server_address, max_response_len).await?;
+        if connection.features.contains(Features::PROOF_OF_ADDRESS) {
+            connection.challenge(distributor_address).await?;
+        } else if connection.legacy {
+            if !allow_legacy {
+                bail!("Legacy distributor cannot prove it owns {distributor_address:?}");
+            }
+            eprintln!(
+                "WARNING: Legacy distributor cannot prove it owns {distributor_address:?}, \
+                continuing anyway"
+            );
+        } else if allow_unproven {
+            println!("Distributor cannot prove it owns {distributor_address:?}, continuing anyway");
+        } else {
+            bail!("Distributor cannot prove it owns {distributor_address:?}");
+        }
+
+        // The price of the song and the fee of the distributor, per chunk in wei.
+        let cost_per_chunk = self.get_song_info(song_id).await?.price.saturating_add(
+            self.get_distribution_fee(song_id, distributor_address)
+                .await?,
+        );
 
         let mut request_queue = RequestQueue::new(first_chunk_id, last_chunk_id);
         let mut song = Vec::with_capacity(chunk_amount);
+        // The transactions of requests that have not been served yet, as (index, amount, hash).
+        let mut unserved = Vec::new();
+        // The transactions of requests that have been served.
+        let mut served = Vec::new();
 
-        // While our song has not yet been completely downloaded..
-        while !song_is_complete(&song, chunk_amount) {
-            // .. send requests if necessary
-            while let Some((request_id, request_size)) = request_queue.request_now(&song) {
-                println!(
-                    "Requesting chunks {request_id} to {}",
-                    request_id + request_size - 1
-                );
+        let result: eyre::Result<()> = async {
+            // While our song has not yet been completely downloaded..
+            while !song_is_complete(&song, chunk_amount) {
+                // .. send requests if necessary
+                while let Some((request_id, request_size)) = request_queue.request_now(&song) {
+                    println!(
+                        "Requesting chunks {request_id} to {}",
+                        request_id + request_size - 1
+                    );
+
+                    let tx_rlp = self
+                        .create_get_chunks_signed_rlp(
+                            song_id,
+                            request_id,
+                            request_size,
+                            distributor_address,
+                        )
+                        .await?;
+                    let (tx_hash, nonce) = hash_and_nonce(&tx_rlp)?;
+                    let fee = cost_per_chunk.saturating_mul(request_size.into())
+                        / U256::from(WEI_PER_IOTA);
+                    database
+                        .add_spending(
+                            tx_hash,
+                            &song_id,
+                            distributor_address,
+                            request_id as u32,
+                            request_size as u32,
+                            fee.low_u64(),
+                            nonce.low_u64(),
+                        )
+                        .await?;
+                    unserved.push((request_id, request_size, tx_hash));
 
-                let tx_rlp = self
-                    .create_get_chunks_signed_rlp(
-                        song_id,
-                        request_id,
-                        request_size,
-                        distributor_address,
-                    )
+                    connection.write_stream.send(&tx_rlp.0).await?;
+                    database.set_spending_status(tx_hash, "sent").await?;
+                }
+
+                // And then read the next response
+                self.write_next_chunks_to_buffer(&mut connection.read_stream, &mut song, &song_id)
                     .await?;
-                write_stream.send(&tx_rlp.0).await?;
+
+                let received_until = first_chunk_id + div_ceil(song.len(), BYTES_PER_CHUNK_USIZE);
+                let is_served =
+                    |(index, amount, _): &(usize, usize, H256)| index + amount <= received_until;
+                for (_, _, tx_hash) in unserved.iter().filter(|request| is_served(request)) {
+                    database.set_spending_status(*tx_hash, "served").await?;
+                    served.push(*tx_hash);
+                }
+                unserved.retain(|request| !is_served(request));
             }
+            Ok(())
+        }
+        .await;
 
-            // And then read the next response
-            self.write_next_chunks_to_buffer(&mut read_stream, &mut song, &song_id)
-                .await?
+        if let Err(e) = result {
+            for (_, _, tx_hash) in unserved {
+                if let Err(e) = database.set_spending_status(tx_hash, "failed").await {
+                    eprintln!("Could not record the failure of payment {tx_hash:?}: {e:#}");
+                }
+            }
+            return Err(e);
         }
         println!("Song downloaded and verified!");
+
+        // The song has been paid for, so it is returned even if the status cannot be recorded.
+        if let Err(e) = self.record_mined_payments(database, served).await {
+            eprintln!("Could not record the on-chain status of the payments: {e:#}");
+        }
         Ok(song)
     }
 
+    /// Wait until the transactions are mined, and record whether they were `mined` or `reverted`.
+    /// Transactions that are not mined within the confirmation-timeout keep their status.
+    async fn record_mined_payments(
+        &self,
+        database: Database,
+        mut tx_hashes: Vec<H256>,
+    ) -> eyre::Result<()> {
+        let deadline = Instant::now() + CONFIRMATION_TIMEOUT;
+        loop {
+            let mut pending = Vec::new();
+            for tx_hash in tx_hashes {
+                match self.get_transaction_receipt(tx_hash).await? {
+                    Some(receipt) => {
+                        let status = match receipt.status {
+                            Some(status) if status.is_zero() => "reverted",
+                            _ => "mined",
+                        };
+                        database.set_spending_status(tx_hash, status).await?;
+                    }
+                    None => pending.push(tx_hash),
+                }
+            }
+            if pending.is_empty() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "{} payments were not mined within {CONFIRMATION_TIMEOUT:?}",
+                    pending.len()
+                );
+            }
+            tx_hashes = pending;
+            tokio::time::sleep(self.poll_interval()).await;
+        }
+    }
+
     /// Reads the next chunk from the stream and adds them to the buffer.
     async fn write_next_chunks_to_buffer(
         &self,
-        read_stream: &mut (impl Stream<Item = eyre::Result<(u32, BytesMut)>> + Unpin),
+        read_stream: &mut (impl Stream<Item = Result<SendChunksFrame, FrameError>> + Unpin),
         buffer: &mut Vec<u8>,
         song_id: &SongId,
     ) -> eyre::Result<()> {
@@ -108,7 +246,15 @@ impl TangleTunesClient {
         let result = read_stream.next().await.ok_or(eyre!(
             "Distributor closed stream before all data was received"
         ))?;
-        let (start_chunk_id, chunks) = result?;
+        let (start_chunk_id, chunks) = match result? {
+            SendChunksFrame::Chunks(start_chunk_id, chunks) => (start_chunk_id, chunks),
+            SendChunksFrame::Control(ControlFrame::Error { code, message }) => {
+                return Err(RefusedError { code, message }.into())
+            }
+            SendChunksFrame::Control(frame) => {
+                bail!("Unexpected control-frame from distributor: {frame:?}")
+            }
+        };
         println!(
             "Received {} bytes starting at id {start_chunk_id}",
             chunks.len()
@@ -130,6 +276,114 @@ impl TangleTunesClient {
     }
 }
 
+/// An open connection to a distributor, after the handshake.
+struct DistributorConnection {
+    read_stream: FramedRead<ReadHalf<Box<dyn Connection>>, SendChunksDecoder>,
+    write_stream: FramedWrite<WriteHalf<Box<dyn Connection>>, RequestChunksEncoder>,
+    features: Features,
+    /// Whether the distributor speaks the legacy protocol, without a handshake.
+    legacy: bool,
+    /// The hash of the certificate of the distributor, if connected over TLS.
+    channel_binding: Option<[u8; 32]>,
+}
+
+impl DistributorConnection {
+    /// Open a plaintext or TLS connection to the distributor, without a handshake.
+    async fn open(server_address: ServerAddress, max_response_len: u32) -> eyre::Result<Self> {
+        let (stream, channel_binding): (Box<dyn Connection>, _) = if server_address.tls {
+            let (stream, cert_hash) = tls::connect(server_address.socket_address).await?;
+            (Box::new(stream), Some(cert_hash))
+        } else {
+            let stream = TcpStream::connect(server_address.socket_address).await?;
+            (Box::new(stream), None)
+        };
+
+        let (read_stream, write_stream) = tokio::io::split(stream);
+        Ok(Self {
+            read_stream: FramedRead::new(
+                read_stream,
+                SendChunksDecoder::with_max_len(max_response_len),
+            ),
+            write_stream: FramedWrite::new(write_stream, RequestChunksEncoder),
+            features: Features::LEGACY,
+            legacy: true,
+            channel_binding,
+        })
+    }
+
+    /// Connect to the distributor and perform the handshake.
+    ///
+    /// Distributors that only speak the legacy protocol close the connection without a response
+    /// when they receive our hello-frame, in which case we connect again without a handshake. Any
+    /// other response that is not a valid frame fails the connection.
+    async fn connect(server_address: ServerAddress, max_response_len: u32) -> eyre::Result<Self> {
+        let mut connection = Self::open(server_address, max_response_len).await?;
+
+        connection.write_stream.send(&ControlFrame::hello()).await?;
+        match connection.read_stream.next().await {
+            Some(Ok(SendChunksFrame::Control(ControlFrame::Welcome {
+                version,
+                software,
+                features,
+            }))) => {
+                if version != PROTOCOL_VERSION {
+                    bail!(
+                        "Distributor speaks protocol version {version}, we speak {PROTOCOL_VERSION}"
+                    );
+                }
+                println!("Distributor runs version {software} with features {features:?}");
+                connection.features = features;
+                connection.legacy = false;
+                Ok(connection)
+            }
+            Some(Ok(SendChunksFrame::Control(ControlFrame::Error { code, message }))) => {
+                Err(RefusedError { code, message }.into())
+            }
+            Some(Ok(frame)) => bail!("Expected a welcome-frame from distributor, got {frame:?}"),
+            Some(Err(e)) => Err(eyre::Report::new(e).wrap_err("Handshake with distributor failed")),
+            None => {
+                println!("Distributor speaks the legacy protocol, reconnecting without handshake");
+                Self::open(server_address, max_response_len).await
+            }
+        }
+    }
+
+    /// Send a challenge to the distributor, and check that it is signed by the
+    /// distributor-address.
+    async fn challenge(&mut self, distributor_address: Address) -> eyre::Result<()> {
+        let nonce: [u8; 32] = rand::random();
+        self.write_stream
+            .send(&ControlFrame::Challenge { nonce })
+            .await?;
+
+        match self.read_stream.next().await {
+            Some(Ok(SendChunksFrame::Control(ControlFrame::Proof { signature }))) => {
+                crypto::verify_challenge(
+                    &nonce,
+                    self.channel_binding.as_ref(),
+                    &signature,
+                    distributor_address,
+                )
+                .wrap_err(format!(
+                    "Distributor could not prove it owns {distributor_address:?}"
+                ))
+            }
+            Some(Ok(SendChunksFrame::Control(ControlFrame::Error { code, message }))) => {
+                Err(RefusedError { code, message }.into())
+            }
+            Some(Ok(frame)) => bail!("Expected a proof-frame from distributor, got {frame:?}"),
+            Some(Err(e)) => Err(e.into()),
+            None => bail!("Distributor closed stream before proving its address"),
+        }
+    }
+}
+
+/// The hash and nonce of a signed transaction.
+fn hash_and_nonce(tx_rlp: &Bytes) -> eyre::Result<(H256, U256)> {
+    let (tx, signature) = TypedTransaction::decode_signed(&Rlp::new(tx_rlp))?;
+    Ok((tx.hash(&signature), tx.nonce().copied().unwrap_or_default()))
+}
+
 /// Whether the song is completely downloaded, given the amount of chunks that it should contain.
 fn song_is_complete(song: &[u8], chunks: usize) -> bool {
     song.len() + BYTES_PER_CHUNK_USIZE > (chunks * BYTES_PER_CHUNK_USIZE)
@@ -176,12 +430,114 @@ impl RequestQueue {
 #[cfg(test)]
 mod test {
     use crate::{
-        library::client::download::{RequestQueue, CHUNKS_PER_REQUEST},
-        BYTES_PER_CHUNK_USIZE,
+        command::distribute::test_node::TestNode,
+        library::{
+            app::App,
+            client::{
+                download::{
+                    hash_and_nonce, DistributorConnection, RequestQueue, CHUNKS_PER_REQUEST,
+                },
+                TangleTunesClient,
+            },
+            crypto::Wallet,
+            tcp::{Features, ServerAddress, DEFAULT_MAX_RESPONSE_LEN},
+            util::SongId,
+        },
+        test, BYTES_PER_CHUNK_USIZE,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
     };
 
     use super::song_is_complete;
 
+    /// A distributor that answers the first connection with the response, and then accepts
+    /// another connection.
+    async fn distributor(response: &'static [u8]) -> eyre::Result<ServerAddress> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = ServerAddress {
+            socket_address: listener.local_addr()?,
+            tls: false,
+        };
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let _hello = stream.read(&mut [0; 1024]).await?;
+            stream.write_all(response).await?;
+            drop(stream);
+            let (_stream, _) = listener.accept().await?;
+            std::future::pending::<()>().await;
+            Ok::<_, eyre::Report>(())
+        });
+        Ok(address)
+    }
+
+    #[tokio::test]
+    async fn on_chain_status_of_payments_is_recorded() -> eyre::Result<()> {
+        let node = TestNode::start().await?;
+        node.mine();
+        let app = App::init_with_node(&node.url).await?;
+        let wallet = Wallet::generate(test::CHAIN_ID);
+        let listener = TangleTunesClient::initialize_offline(wallet, &app.contract_address)?;
+        let song = SongId::try_from_hex(test::HEX_ID_1)?;
+
+        // The distributor sends the transactions, of which the second one reverts.
+        let mut tx_hashes = Vec::new();
+        for index in [0, 10] {
+            let raw = listener
+                .create_get_chunks_signed_rlp(song, index, 10, app.client.wallet_address())
+                .await?;
+            let (tx_hash, nonce) = hash_and_nonce(&raw)?;
+            let distributor = app.client.wallet_address();
+            app.database
+                .add_spending(
+                    tx_hash,
+                    &song,
+                    distributor,
+                    index as u32,
+                    10,
+                    10,
+                    nonce.low_u64(),
+                )
+                .await?;
+            app.database.set_spending_status(tx_hash, "served").await?;
+            tx_hashes.push(tx_hash);
+            app.client.send_raw_tx(raw).await?;
+        }
+        node.revert(tx_hashes[1]);
+
+        app.client
+            .record_mined_payments(app.database, tx_hashes)
+            .await?;
+        let statuses: Vec<_> = app
+            .database
+            .get_spending()
+            .await?
+            .into_iter()
+            .map(|spending| spending.7)
+            .collect();
+        assert_eq!(statuses, ["mined", "reverted"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn legacy_distributor_is_connected_without_handshake() -> eyre::Result<()> {
+        let address = distributor(&[]).await?;
+        let connection = DistributorConnection::connect(address, DEFAULT_MAX_RESPONSE_LEN).await?;
+        assert_eq!(connection.features, Features::LEGACY);
+        assert!(connection.legacy);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_handshake_response_fails() -> eyre::Result<()> {
+        // A control-frame of u32::MAX bytes, which is larger than the maximum length.
+        let address = distributor(&[0xff; 8]).await?;
+        let result = DistributorConnection::connect(address, DEFAULT_MAX_RESPONSE_LEN).await;
+        assert!(result.is_err());
+        Ok(())
+    }
+
     #[test]
     fn song_is_complete_test() {
         assert!(song_is_complete(&[0; 1], 1));
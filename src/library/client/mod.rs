@@ -1,35 +1,34 @@
 mod calls;
 mod download;
+mod transport;
+mod validation;
+
+pub use transport::MeteredHttp;
+pub use validation::{GetChunksTx, InvalidTxError};
 
 pub const GAS: usize = 1_000_000;
 pub const WEI_PER_IOTA: u128 = 1_000_000_000_000;
 
 use super::{
-    abi::{GetChunksCall, TangleTunesAbi},
+    abi::TangleTunesAbi,
     crypto::Wallet,
     util::{TTCallExt, TransactionReceiptExt},
 };
-use crate::library::util::SongId;
-use ethers::{
-    abi::AbiDecode,
-    prelude::*,
-    signers::LocalWallet,
-    types::{transaction::eip2718::TypedTransaction, Address},
-    utils::rlp::{Decodable, Rlp},
-};
+use crate::library::{metrics::METRICS, util::SongId};
+use ethers::{prelude::*, signers::LocalWallet, types::Address};
 use ethers_core::k256::ecdsa::SigningKey;
-use ethers_providers::{Http, Middleware, Provider};
+use ethers_providers::{is_local_endpoint, Middleware, Provider, DEFAULT_LOCAL_POLL_INTERVAL};
 use itertools::Itertools;
-use std::{ops::Deref, str::FromStr, sync::Arc};
+use std::{ops::Deref, str::FromStr, sync::Arc, time::Duration};
 
-pub type TTMiddleWare = NonceManagerMiddleware<SignerMiddleware<Provider<Http>, LocalWallet>>;
+pub type TTMiddleWare =
+    NonceManagerMiddleware<SignerMiddleware<Provider<MeteredHttp>, LocalWallet>>;
 pub type TTCall<T> = ContractCall<TTMiddleWare, T>;
 
 /// The client used to connect to the IOTA network.
 #[derive(Debug)]
 pub struct TangleTunesClient {
-    pub abi_client:
-        TangleTunesAbi<NonceManagerMiddleware<SignerMiddleware<Provider<Http>, LocalWallet>>>,
+    pub abi_client: TangleTunesAbi<TTMiddleWare>,
 }
 
 impl TangleTunesClient {
@@ -37,22 +36,32 @@ impl TangleTunesClient {
         &self,
         hash: impl Into<TxHash>,
         confirmations: usize,
-    ) -> PendingTransaction<'_, Http> {
+    ) -> PendingTransaction<'_, MeteredHttp> {
         PendingTransaction::new(hash.into(), self.abi_client.client_ref().inner().inner())
             .confirmations(confirmations)
     }
 
+    /// The interval at which the node is polled for pending transactions.
+    pub fn poll_interval(&self) -> Duration {
+        self.abi_client.client_ref().inner().inner().get_interval()
+    }
+
     pub async fn initialize(
         wallet: Wallet,
         node_url: &str,
         contract_address: &str,
     ) -> eyre::Result<Self> {
         let wallet_address = wallet.address();
+        // Like `Provider::try_from`, transactions on a local node are polled more often.
+        let mut provider = Provider::new(MeteredHttp::new(node_url)?);
+        if is_local_endpoint(node_url) {
+            provider.set_interval(DEFAULT_LOCAL_POLL_INTERVAL);
+        }
         let contract = Self {
             abi_client: TangleTunesAbi::new(
                 Address::from_str(contract_address).unwrap(),
                 Arc::new(
-                    Provider::try_from(node_url)?
+                    provider
                         .with_signer(wallet.local_wallet().clone())
                         .nonce_manager(wallet_address),
                 ),
@@ -68,11 +77,52 @@ impl TangleTunesClient {
         Ok(contract)
     }
 
+    /// Creates a client without contacting the node, the nonce starts at 0.
+    #[cfg(test)]
+    pub fn initialize_offline(wallet: Wallet, contract_address: &str) -> eyre::Result<Self> {
+        let wallet_address = wallet.address();
+        Ok(Self {
+            abi_client: TangleTunesAbi::new(
+                Address::from_str(contract_address)?,
+                Arc::new(
+                    Provider::new(MeteredHttp::new("http://127.0.0.1:1")?)
+                        .with_signer(wallet.local_wallet().clone())
+                        .nonce_manager(wallet_address),
+                ),
+            ),
+        })
+    }
+
     pub async fn l2_balance(&self) -> eyre::Result<U256> {
+        self.l2_balance_of(self.wallet_address()).await
+    }
+
+    pub async fn l2_balance_of(&self, address: Address) -> eyre::Result<U256> {
         Ok(self
             .abi_client
             .client_ref()
-            .get_balance(self.wallet_address(), None)
+            .get_balance(address, None)
+            .await?)
+    }
+
+    /// Get the nonce of the next transaction of the address, including pending transactions.
+    pub async fn get_transaction_count(&self, address: Address) -> eyre::Result<U256> {
+        Ok(self
+            .abi_client
+            .client_ref()
+            .get_transaction_count(address, Some(BlockNumber::Pending.into()))
+            .await?)
+    }
+
+    /// Get the receipt of the transaction, if it has been included in a block.
+    pub async fn get_transaction_receipt(
+        &self,
+        hash: TxHash,
+    ) -> eyre::Result<Option<TransactionReceipt>> {
+        Ok(self
+            .abi_client
+            .client_ref()
+            .get_transaction_receipt(hash)
             .await?)
     }
 
@@ -118,23 +168,17 @@ impl TangleTunesClient {
         Ok(tx.rlp_signed(&signature))
     }
 
-    pub fn decode_get_chunks_params(&self, tx_rlp: &[u8]) -> eyre::Result<GetChunksCall> {
-        let tx = TypedTransaction::decode(&Rlp::new(tx_rlp))?;
-        Ok(AbiDecode::decode(tx.data().unwrap())?)
-    }
-
     pub async fn send_raw_tx(
         &self,
         tx: Bytes,
-    ) -> Result<PendingTransaction<'_, Http>, eyre::Report> {
-        Ok(self
-            .abi_client
+    ) -> Result<PendingTransaction<'_, MeteredHttp>, ProviderError> {
+        self.abi_client
             .deref()
             .client_ref()
             .inner()
             .inner()
             .send_raw_transaction(tx)
-            .await?)
+            .await
     }
 
     pub async fn get_local_nonce(&self) -> eyre::Result<U256> {
@@ -157,6 +201,8 @@ impl TangleTunesClient {
     /// are not actually distributing the songs already. It will only distribute those songs that
     /// are not yet distributed.
     pub async fn try_distribute(&'static self, songs: &[(SongId, U256)]) -> eyre::Result<()> {
+        let song_ids = songs.iter().map(|(song, _fee)| *song).collect_vec();
+
         // Check which songs we are already distributing
         let distributions = self
             .abi_client
@@ -177,6 +223,7 @@ impl TangleTunesClient {
             .collect();
 
         if songs.is_empty() {
+            METRICS.set_songs_registered(&song_ids, true);
             return Ok(());
         }
 
@@ -188,6 +235,7 @@ impl TangleTunesClient {
             .await?
             .status_is_ok(&format!("Could not register song with ids {songs:?}"))?;
 
+        METRICS.set_songs_registered(&song_ids, true);
         Ok(())
     }
 
@@ -207,6 +255,7 @@ impl TangleTunesClient {
             .await?;
 
         // Only deregister for the songs where we are distributing
+        let all_songs = songs;
         let songs: Vec<SongId> = songs
             .iter()
             .zip(distributions)
@@ -214,6 +263,7 @@ impl TangleTunesClient {
             .collect();
 
         if songs.is_empty() {
+            METRICS.set_songs_registered(all_songs, false);
             return Ok(());
         }
 
@@ -225,6 +275,7 @@ impl TangleTunesClient {
             .await?
             .status_is_ok(&format!("Could not deregister song with ids {songs:?}"))?;
 
+        METRICS.set_songs_registered(all_songs, false);
         Ok(())
     }
 
@@ -0,0 +1,32 @@
+use crate::library::metrics::METRICS;
+use async_trait::async_trait;
+use ethers_providers::{Http, HttpClientError, JsonRpcClient};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Debug, str::FromStr};
+use tokio::time::Instant;
+
+/// The http-transport to the node, which records the latency of every request in the metrics.
+#[derive(Debug, Clone)]
+pub struct MeteredHttp(Http);
+
+impl MeteredHttp {
+    pub fn new(url: &str) -> eyre::Result<Self> {
+        Ok(Self(Http::from_str(url)?))
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for MeteredHttp {
+    type Error = HttpClientError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let start = Instant::now();
+        let result = self.0.request(method, params).await;
+        METRICS.observe_rpc_latency(method, start.elapsed());
+        result
+    }
+}
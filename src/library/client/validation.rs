@@ -0,0 +1,299 @@
+use super::TangleTunesClient;
+use crate::library::abi::GetChunksCall;
+use ethers::{
+    abi::{AbiDecode, AbiEncode, AbiError},
+    signers::Signer,
+    types::{
+        transaction::eip2718::{TypedTransaction, TypedTransactionError},
+        Address, BlockNumber, Bytes, SignatureError, TransactionRequest, H256, U256, U64,
+    },
+    utils::rlp::Rlp,
+};
+use ethers_providers::{Middleware, ProviderError};
+
+/// The minimum gas-limit a get-chunks transaction must have to be accepted.
+pub const MIN_GET_CHUNKS_GAS: u64 = 100_000;
+/// The minimum gas-price a get-chunks transaction must have to be accepted.
+pub const MIN_GAS_PRICE: u64 = 1;
+
+/// A signed get-chunks transaction received from a listener that passed validation.
+#[derive(Debug, Clone)]
+pub struct GetChunksTx {
+    /// The signed rlp-encoded transaction, as received.
+    pub raw: Bytes,
+    pub hash: H256,
+    /// The address recovered from the signature.
+    pub sender: Address,
+    pub nonce: U256,
+    pub gas: U256,
+    pub gas_price: U256,
+    pub params: GetChunksCall,
+}
+
+impl GetChunksTx {
+    /// The maximum amount of wei this transaction can spend on gas.
+    pub fn max_gas_cost(&self) -> U256 {
+        self.gas.saturating_mul(self.gas_price)
+    }
+}
+
+/// The reason a get-chunks transaction was rejected.
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidTxError {
+    #[error("Transaction could not be decoded: {0}")]
+    Decode(#[from] TypedTransactionError),
+    #[error("Could not recover the signer of the transaction: {0}")]
+    Signature(#[from] SignatureError),
+    #[error("Transaction is sent to {got:?} instead of the contract at {expected:?}")]
+    WrongRecipient {
+        got: Option<Address>,
+        expected: Address,
+    },
+    #[error("Transaction has chain-id {got:?} instead of {expected}")]
+    WrongChainId { got: Option<U64>, expected: U64 },
+    #[error("Transaction has gas-limit {got}, at least {MIN_GET_CHUNKS_GAS} is required")]
+    GasLimitTooLow { got: U256 },
+    #[error("Transaction has gas-price {got}, at least {MIN_GAS_PRICE} is required")]
+    GasPriceTooLow { got: U256 },
+    #[error("Transaction transfers a value of {0}, which get-chunks does not accept")]
+    NonZeroValue(U256),
+    #[error("Transaction is not a get-chunks call: {0}")]
+    NotGetChunks(#[from] AbiError),
+    #[error("Transaction is for distributor {got:?} instead of {expected:?}")]
+    WrongDistributor { got: Address, expected: Address },
+    #[error("Transaction requests an invalid range: index {index}, amount {amount}")]
+    InvalidRange { index: U256, amount: U256 },
+    #[error("Sender {sender:?} cannot pay for gas: (balance = {balance}, required = {required})")]
+    InsufficientGasFunds {
+        sender: Address,
+        balance: U256,
+        required: U256,
+    },
+    #[error("Transaction would revert: {0}")]
+    WouldRevert(String),
+}
+
+impl TangleTunesClient {
+    /// Decodes a signed get-chunks transaction and checks that it is addressed to our contract on
+    /// our chain, is meant for us as a distributor and has a usable gas-limit and gas-price.
+    ///
+    /// This does not check whether the sender has the funds to pay for the gas, since that requires
+    /// a lookup of the sender's balance.
+    pub fn validate_get_chunks_tx(&self, raw: Bytes) -> Result<GetChunksTx, InvalidTxError> {
+        let (tx, signature) = TypedTransaction::decode_signed(&Rlp::new(&raw))?;
+        let sender = signature.recover(tx.sighash())?;
+
+        let contract_address = self.abi_client.address();
+        if tx.to_addr() != Some(&contract_address) {
+            return Err(InvalidTxError::WrongRecipient {
+                got: tx.to_addr().copied(),
+                expected: contract_address,
+            });
+        }
+
+        let chain_id = U64::from(self.wallet().chain_id());
+        if tx.chain_id() != Some(chain_id) {
+            return Err(InvalidTxError::WrongChainId {
+                got: tx.chain_id(),
+                expected: chain_id,
+            });
+        }
+
+        let gas = tx.gas().copied().unwrap_or_default();
+        if gas < MIN_GET_CHUNKS_GAS.into() {
+            return Err(InvalidTxError::GasLimitTooLow { got: gas });
+        }
+
+        let gas_price = tx.gas_price().unwrap_or_default();
+        if gas_price < MIN_GAS_PRICE.into() {
+            return Err(InvalidTxError::GasPriceTooLow { got: gas_price });
+        }
+
+        if let Some(value) = tx.value().filter(|value| !value.is_zero()) {
+            return Err(InvalidTxError::NonZeroValue(*value));
+        }
+
+        let params: GetChunksCall = AbiDecode::decode(tx.data().cloned().unwrap_or_default())?;
+        if params.distributor != self.wallet_address() {
+            return Err(InvalidTxError::WrongDistributor {
+                got: params.distributor,
+                expected: self.wallet_address(),
+            });
+        }
+
+        // Both index and amount are handled as u32 chunk-ids, so they must fit.
+        let in_range = params.amount > U256::zero()
+            && params.amount <= u32::MAX.into()
+            && params.index <= u32::MAX.into()
+            && params.index + params.amount <= U256::from(u32::MAX);
+        if !in_range {
+            return Err(InvalidTxError::InvalidRange {
+                index: params.index,
+                amount: params.amount,
+            });
+        }
+
+        Ok(GetChunksTx {
+            hash: tx.hash(&signature),
+            raw,
+            sender,
+            nonce: tx.nonce().copied().unwrap_or_default(),
+            gas,
+            gas_price,
+            params,
+        })
+    }
+
+    /// Dry-runs the get-chunks transaction with an eth-call against the pending state of the node.
+    ///
+    /// A transaction that would revert results in an error-response of the node.
+    pub async fn simulate_get_chunks_tx(&self, tx: &GetChunksTx) -> Result<(), ProviderError> {
+        self.abi_client
+            .client_ref()
+            .inner()
+            .inner()
+            .call(
+                &self.get_chunks_call_request(tx),
+                Some(BlockNumber::Pending.into()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The call-request that executes the get-chunks transaction as its sender.
+    fn get_chunks_call_request(&self, tx: &GetChunksTx) -> TypedTransaction {
+        TransactionRequest::new()
+            .from(tx.sender)
+            .to(self.abi_client.address())
+            .data(tx.params.clone().encode())
+            .gas(tx.gas)
+            .gas_price(tx.gas_price)
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        library::{client::TangleTunesClient, crypto::Wallet, util::SongId},
+        test,
+    };
+
+    const CONTRACT_ADDRESS: &str = "0x8fA1fc1Eec824a36fD31497EAa8716Fc9C446d51";
+
+    fn client(chain_id: u16, contract_address: &str) -> TangleTunesClient {
+        TangleTunesClient::initialize_offline(Wallet::generate(chain_id), contract_address).unwrap()
+    }
+
+    #[tokio::test]
+    async fn valid_tx_is_accepted() -> eyre::Result<()> {
+        let distributor = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let listener = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let song_id = SongId::try_from_hex(test::HEX_ID_1)?;
+
+        let raw = listener
+            .create_get_chunks_signed_rlp(song_id, 5, 10, distributor.wallet_address())
+            .await?;
+        let tx = distributor.validate_get_chunks_tx(raw)?;
+
+        assert_eq!(tx.sender, listener.wallet_address());
+        assert_eq!(SongId::from(tx.params.song), song_id);
+        assert_eq!(tx.params.index, 5.into());
+        assert_eq!(tx.params.amount, 10.into());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrong_distributor_is_rejected() -> eyre::Result<()> {
+        let distributor = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let listener = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let song_id = SongId::try_from_hex(test::HEX_ID_1)?;
+
+        let raw = listener
+            .create_get_chunks_signed_rlp(song_id, 0, 10, listener.wallet_address())
+            .await?;
+        assert!(matches!(
+            distributor.validate_get_chunks_tx(raw),
+            Err(InvalidTxError::WrongDistributor { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrong_chain_id_is_rejected() -> eyre::Result<()> {
+        let distributor = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let listener = client(test::CHAIN_ID + 1, CONTRACT_ADDRESS);
+        let song_id = SongId::try_from_hex(test::HEX_ID_1)?;
+
+        let raw = listener
+            .create_get_chunks_signed_rlp(song_id, 0, 10, distributor.wallet_address())
+            .await?;
+        assert!(matches!(
+            distributor.validate_get_chunks_tx(raw),
+            Err(InvalidTxError::WrongChainId { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrong_contract_is_rejected() -> eyre::Result<()> {
+        let distributor = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let listener = client(test::CHAIN_ID, "0x0000000000000000000000000000000000000001");
+        let song_id = SongId::try_from_hex(test::HEX_ID_1)?;
+
+        let raw = listener
+            .create_get_chunks_signed_rlp(song_id, 0, 10, distributor.wallet_address())
+            .await?;
+        assert!(matches!(
+            distributor.validate_get_chunks_tx(raw),
+            Err(InvalidTxError::WrongRecipient { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_range_is_rejected() -> eyre::Result<()> {
+        let distributor = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let listener = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let song_id = SongId::try_from_hex(test::HEX_ID_1)?;
+
+        let raw = listener
+            .create_get_chunks_signed_rlp(song_id, 0, 0, distributor.wallet_address())
+            .await?;
+        assert!(matches!(
+            distributor.validate_get_chunks_tx(raw),
+            Err(InvalidTxError::InvalidRange { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn call_request_matches_tx() -> eyre::Result<()> {
+        let distributor = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let listener = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        let song_id = SongId::try_from_hex(test::HEX_ID_1)?;
+
+        let raw = listener
+            .create_get_chunks_signed_rlp(song_id, 5, 10, distributor.wallet_address())
+            .await?;
+        let (signed, _signature) = TypedTransaction::decode_signed(&Rlp::new(&raw))?;
+        let tx = distributor.validate_get_chunks_tx(raw)?;
+
+        let call = distributor.get_chunks_call_request(&tx);
+        assert_eq!(call.from(), Some(&listener.wallet_address()));
+        assert_eq!(call.to_addr(), signed.to_addr());
+        assert_eq!(call.data(), signed.data());
+        assert_eq!(call.gas(), signed.gas());
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        let distributor = client(test::CHAIN_ID, CONTRACT_ADDRESS);
+        assert!(matches!(
+            distributor.validate_get_chunks_tx(Bytes::from(vec![0xde, 0xad, 0xbe, 0xef])),
+            Err(InvalidTxError::Decode(_))
+        ));
+    }
+}
@@ -1,7 +1,7 @@
 use ethers::{
     prelude::{k256::SecretKey, rand::rngs::ThreadRng},
     signers::{LocalWallet, Signer},
-    types::Address,
+    types::{Address, Signature, SignatureError},
 };
 use eyre::Context;
 use magic_crypt::{new_magic_crypt, MagicCryptTrait};
@@ -44,6 +44,38 @@ impl Wallet {
     pub fn address(&self) -> Address {
         self.wallet.address()
     }
+
+    /// Sign the challenge of a listener, which proves that we hold the key of our address.
+    ///
+    /// For TLS-connections, the hash of our certificate binds the signature to the connection.
+    pub async fn sign_challenge(
+        &self,
+        nonce: &[u8; 32],
+        channel_binding: Option<&[u8; 32]>,
+    ) -> eyre::Result<Signature> {
+        let message = challenge_message(nonce, channel_binding);
+        Ok(self.wallet.sign_message(message).await?)
+    }
+}
+
+/// Verify that the challenge was signed by the given address.
+pub fn verify_challenge(
+    nonce: &[u8; 32],
+    channel_binding: Option<&[u8; 32]>,
+    signature: &Signature,
+    address: Address,
+) -> Result<(), SignatureError> {
+    signature.verify(challenge_message(nonce, channel_binding), address)
+}
+
+/// The message that is signed for a challenge, which is prefixed so that the signature cannot be
+/// used for anything else.
+fn challenge_message(nonce: &[u8; 32], channel_binding: Option<&[u8; 32]>) -> Vec<u8> {
+    let mut message = [b"TangleTunes distributor challenge:".as_slice(), nonce].concat();
+    if let Some(channel_binding) = channel_binding {
+        message.extend_from_slice(channel_binding);
+    }
+    message
 }
 
 /// Encrypt the hex-encoded secret key with the password.
@@ -91,4 +123,16 @@ mod test {
         assert_eq!(wallet1.address(), wallet2.address());
         assert_eq!(wallet1.private_key(), wallet2.private_key());
     }
+
+    #[tokio::test]
+    async fn challenge_is_verified() {
+        let wallet = Wallet::generate(test::CHAIN_ID);
+        let (nonce, binding) = ([7; 32], [9; 32]);
+        let signature = wallet.sign_challenge(&nonce, Some(&binding)).await.unwrap();
+
+        assert!(verify_challenge(&nonce, Some(&binding), &signature, wallet.address()).is_ok());
+        assert!(verify_challenge(&[8; 32], Some(&binding), &signature, wallet.address()).is_err());
+        assert!(verify_challenge(&nonce, None, &signature, wallet.address()).is_err());
+        assert!(verify_challenge(&nonce, Some(&binding), &signature, Address::random()).is_err());
+    }
 }
@@ -1,11 +1,16 @@
 use crate::library::util::SongId;
 use crate::BYTES_PER_CHUNK;
 use chrono::{DateTime, Utc};
+use ethers::{
+    types::{Address, H256, U256},
+    utils::keccak256,
+};
 
 use futures::executor::block_on;
+use num_integer::div_ceil;
 use once_cell::sync::OnceCell;
 use sqlx::pool::PoolConnection;
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePoolOptions};
 use sqlx::{Pool, Sqlite};
 use std::fmt::Debug;
 use std::path::Path;
@@ -55,10 +60,18 @@ impl Database {
             "
             CREATE TABLE IF NOT EXISTS songs (
                 id BLOB PRIMARY KEY,
-                data BLOB NOT NULL,
+                len INT NOT NULL,
                 inserted_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
             );
-    
+
+            CREATE TABLE IF NOT EXISTS song_chunks (
+                song BLOB NOT NULL,
+                idx INT NOT NULL,
+                data BLOB NOT NULL,
+                hash BLOB NOT NULL,
+                PRIMARY KEY (song, idx)
+            );
+
             CREATE TABLE IF NOT EXISTS song_list (
                 idx INT PRIMARY KEY,
                 id BLOB NOT NULL UNIQUE
@@ -68,11 +81,140 @@ impl Database {
                 key TEXT PRIMARY KEY,
                 encrypted BOOL
             );
+
+            CREATE TABLE IF NOT EXISTS listener_debt (
+                address BLOB PRIMARY KEY,
+                debt INT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS pending_payments (
+                tx_hash BLOB PRIMARY KEY,
+                listener BLOB NOT NULL,
+                raw BLOB NOT NULL,
+                chunks INT NOT NULL,
+                received_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                song BLOB,
+                fee BLOB
+            );
+
+            CREATE TABLE IF NOT EXISTS earnings (
+                tx_hash BLOB PRIMARY KEY,
+                song BLOB NOT NULL,
+                listener BLOB NOT NULL,
+                chunks INT NOT NULL,
+                fee INT NOT NULL,
+                block_number INT,
+                earned_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS payment_failures (
+                tx_hash BLOB PRIMARY KEY,
+                listener BLOB NOT NULL,
+                kind TEXT NOT NULL,
+                message TEXT NOT NULL,
+                failed_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS spending (
+                tx_hash BLOB PRIMARY KEY,
+                song BLOB NOT NULL,
+                distributor BLOB NOT NULL,
+                first_chunk INT NOT NULL,
+                chunks INT NOT NULL,
+                fee INT NOT NULL,
+                nonce INT NOT NULL,
+                status TEXT NOT NULL,
+                signed_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
+            );
             ",
         )
         .execute(&mut self.acquire().await?)
         .await?;
 
+        self.migrate_song_blobs().await?;
+        self.migrate_pending_payments().await
+    }
+
+    /// Add the song and fee to the pending payments, which older versions did not store. They
+    /// stay empty for the payments that were already pending.
+    async fn migrate_pending_payments(&self) -> eyre::Result<()> {
+        let mut conn = self.acquire().await?;
+        let (has_fee,) = sqlx::query_as::<_, (bool,)>(
+            "
+            SELECT COUNT(*) > 0 FROM pragma_table_info('pending_payments') WHERE name = 'fee';
+            ",
+        )
+        .fetch_one(&mut conn)
+        .await?;
+        if has_fee {
+            return Ok(());
+        }
+
+        sqlx::query(
+            "
+            ALTER TABLE pending_payments ADD COLUMN song BLOB;
+            ALTER TABLE pending_payments ADD COLUMN fee BLOB;
+            ",
+        )
+        .execute(&mut conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Move the songs that older versions stored as a single blob in the `songs` table to the
+    /// `song_chunks` table, with a row and hash per chunk.
+    async fn migrate_song_blobs(&self) -> eyre::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let (has_blobs,) = sqlx::query_as::<_, (bool,)>(
+            "
+            SELECT COUNT(*) > 0 FROM pragma_table_info('songs') WHERE name = 'data';
+            ",
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        if !has_blobs {
+            return Ok(());
+        }
+
+        let ids = sqlx::query_as::<_, (Vec<u8>,)>(
+            "
+            SELECT id FROM songs;
+            ",
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        println!("Migrating {} songs to chunks..", ids.len());
+
+        // The songs are read one at a time, since they can be large
+        for (id,) in ids {
+            let (data,) = sqlx::query_as::<_, (Vec<u8>,)>(
+                "
+                SELECT data FROM songs WHERE id = ?1;
+                ",
+            )
+            .bind(&id)
+            .fetch_one(&mut tx)
+            .await?;
+            insert_chunks(&mut tx, &id, &data).await?;
+        }
+
+        sqlx::query(
+            "
+            CREATE TABLE songs_chunked (
+                id BLOB PRIMARY KEY,
+                len INT NOT NULL,
+                inserted_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
+            );
+            INSERT INTO songs_chunked (id, len, inserted_at)
+                SELECT id, length(data), inserted_at FROM songs;
+            DROP TABLE songs;
+            ALTER TABLE songs_chunked RENAME TO songs;
+            ",
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
         Ok(())
     }
 
@@ -207,17 +349,20 @@ impl Database {
         Ok(row)
     }
 
-    /// Add a song to the database
+    /// Add a song to the database, with a row and hash per chunk.
     pub async fn add_song(&self, id: &SongId, song_data: &[u8]) -> eyre::Result<()> {
+        let mut tx = self.pool.begin().await?;
         sqlx::query(
             "
-            INSERT INTO songs (id, data) VALUES (?1, ?2);
+            INSERT INTO songs (id, len) VALUES (?1, ?2);
             ",
         )
         .bind(id.as_slice())
-        .bind(song_data)
-        .execute(&mut self.acquire().await?)
+        .bind(u32::try_from(song_data.len())?)
+        .execute(&mut tx)
         .await?;
+        insert_chunks(&mut tx, id.as_slice(), song_data).await?;
+        tx.commit().await?;
 
         Ok(())
     }
@@ -248,14 +393,24 @@ impl Database {
     }
 
     pub async fn remove_song(&self, id: &SongId) -> eyre::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "
+            DELETE FROM song_chunks WHERE song = ?1;
+            ",
+        )
+        .bind(id.as_slice())
+        .execute(&mut tx)
+        .await?;
         let res = sqlx::query(
             "
             DELETE FROM songs WHERE id = ?1;
             ",
         )
         .bind(id.as_slice())
-        .execute(&mut self.acquire().await?)
+        .execute(&mut tx)
         .await?;
+        tx.commit().await?;
 
         if res.rows_affected() == 1 {
             Ok(true)
@@ -264,28 +419,472 @@ impl Database {
         }
     }
 
-    /// Get the chunks from (chunk_start, chunk_start + chunks) if they exist.
+    /// Get the length of the song in bytes, or `None` if the song is not stored.
+    pub async fn get_song_len(&self, id: &SongId) -> eyre::Result<Option<u32>> {
+        Ok(sqlx::query_as::<_, (u32,)>(
+            "
+            SELECT len FROM songs WHERE id = ?1
+            ",
+        )
+        .bind(id.as_slice())
+        .fetch_optional(&mut self.acquire().await?)
+        .await?
+        .map(|(len,)| len))
+    }
+
+    /// Get the amount of chunks of the song, or `None` if the song is not stored.
+    pub async fn get_chunk_count(&self, id: &SongId) -> eyre::Result<Option<u32>> {
+        Ok(self
+            .get_song_len(id)
+            .await?
+            .map(|len| div_ceil(len, BYTES_PER_CHUNK)))
+    }
+
+    /// Get the chunks from (chunk_start, chunk_start + chunks) if they exist. Fails if the song is
+    /// not stored, or if any of the chunks before the end of the song are missing.
     pub async fn get_chunks(
         &self,
         id: &SongId,
         chunk_start: u32,
         chunks: u32,
     ) -> eyre::Result<Vec<u8>> {
-        let byte_start = (chunk_start * BYTES_PER_CHUNK) + 1; // First char/byte has i=1 in sqlite
-        let bytes = chunks * BYTES_PER_CHUNK;
+        let mut conn = self.acquire().await?;
+        let Some((len,)) = sqlx::query_as::<_, (u32,)>(
+            "
+            SELECT len FROM songs WHERE id = ?1
+            ",
+        )
+        .bind(id.as_slice())
+        .fetch_optional(&mut conn)
+        .await?
+        else {
+            bail!("Song {id} is not stored");
+        };
+        let chunk_end = Ord::min(
+            chunk_start.saturating_add(chunks),
+            div_ceil(len, BYTES_PER_CHUNK),
+        );
 
-        let row = sqlx::query_as::<_, (Vec<u8>,)>(
+        let rows = sqlx::query_as::<_, (Vec<u8>,)>(
             "
-            SELECT substr(data, ?1, ?2) FROM songs WHERE id = ?3
+            SELECT data FROM song_chunks WHERE song = ?1 AND idx >= ?2 AND idx < ?3 ORDER BY idx
             ",
         )
-        .bind(byte_start)
-        .bind(bytes)
         .bind(id.as_slice())
-        .fetch_one(&mut self.acquire().await?)
+        .bind(chunk_start)
+        .bind(chunk_end)
+        .fetch_all(&mut conn)
+        .await?;
+
+        let expected = chunk_end.saturating_sub(chunk_start) as usize;
+        if rows.len() != expected {
+            bail!(
+                "Song {id} is missing {} of the chunks {chunk_start} to {chunk_end}",
+                expected - rows.len()
+            );
+        }
+        Ok(rows.into_iter().flat_map(|(data,)| data).collect())
+    }
+
+    /// Get the indices of the chunks of the song that are missing or do not match their hash.
+    /// Fails if the song is not stored.
+    pub async fn get_corrupt_chunks(&self, id: &SongId) -> eyre::Result<Vec<u32>> {
+        let Some(chunk_count) = self.get_chunk_count(id).await? else {
+            bail!("Song {id} is not stored");
+        };
+        let rows = sqlx::query_as::<_, (u32, Vec<u8>, Vec<u8>)>(
+            "
+            SELECT idx, data, hash FROM song_chunks WHERE song = ?1 ORDER BY idx
+            ",
+        )
+        .bind(id.as_slice())
+        .fetch_all(&mut self.acquire().await?)
+        .await?;
+
+        let mut valid = vec![false; chunk_count as usize];
+        for (index, data, hash) in rows {
+            if let Some(valid) = valid.get_mut(index as usize) {
+                *valid = keccak256(&data)[..] == hash[..];
+            }
+        }
+        Ok((0..chunk_count)
+            .filter(|index| !valid[*index as usize])
+            .collect())
+    }
+
+    /// Get the debt in chunks of all listeners that have not settled.
+    pub async fn get_listener_debts(&self) -> eyre::Result<Vec<(Address, i64)>> {
+        Ok(sqlx::query_as::<_, (Vec<u8>, i64)>(
+            "
+            SELECT address, debt FROM listener_debt;
+            ",
+        )
+        .fetch_all(&mut self.acquire().await?)
+        .await?
+        .into_iter()
+        .map(|(address, debt)| (Address::from_slice(&address), debt))
+        .collect())
+    }
+
+    /// Set the debt in chunks of the listener, a settled debt is removed.
+    pub async fn set_listener_debt(&self, address: Address, debt: i64) -> eyre::Result<()> {
+        if debt == 0 {
+            sqlx::query(
+                "
+                DELETE FROM listener_debt WHERE address = ?1;
+                ",
+            )
+            .bind(address.as_bytes())
+            .execute(&mut self.acquire().await?)
+            .await?;
+        } else {
+            sqlx::query(
+                "
+                INSERT OR REPLACE INTO listener_debt (address, debt) VALUES (?1, ?2);
+                ",
+            )
+            .bind(address.as_bytes())
+            .bind(debt)
+            .execute(&mut self.acquire().await?)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Store a raw get-chunks transaction of a listener that pays for the given amount of chunks
+    /// of the song, until it is settled. The fee is our fee for all chunks in wei, at the time
+    /// they were served.
+    pub async fn add_pending_payment(
+        &self,
+        listener: Address,
+        tx_hash: H256,
+        raw: &[u8],
+        song: &SongId,
+        chunks: u32,
+        fee: U256,
+    ) -> eyre::Result<()> {
+        let mut fee_bytes = [0; 32];
+        fee.to_big_endian(&mut fee_bytes);
+        sqlx::query(
+            "
+            INSERT OR REPLACE INTO pending_payments (tx_hash, listener, raw, chunks, song, fee)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6);
+            ",
+        )
+        .bind(tx_hash.as_bytes())
+        .bind(listener.as_bytes())
+        .bind(raw)
+        .bind(chunks)
+        .bind(song.as_slice())
+        .bind(fee_bytes.as_slice())
+        .execute(&mut self.acquire().await?)
+        .await?;
+        Ok(())
+    }
+
+    /// Remove a pending get-chunks transaction once it is settled.
+    pub async fn remove_pending_payment(&self, tx_hash: H256) -> eyre::Result<()> {
+        sqlx::query(
+            "
+            DELETE FROM pending_payments WHERE tx_hash = ?1;
+            ",
+        )
+        .bind(tx_hash.as_bytes())
+        .execute(&mut self.acquire().await?)
+        .await?;
+        Ok(())
+    }
+
+    /// Get the get-chunks transactions that have not been settled, as
+    /// `(listener, tx_hash, raw, chunks, (song, fee))` in the order they were received. The song
+    /// and fee are missing for payments that were stored by older versions.
+    #[allow(clippy::type_complexity)]
+    pub async fn get_pending_payments(
+        &self,
+    ) -> eyre::Result<Vec<(Address, H256, Vec<u8>, u32, Option<(SongId, U256)>)>> {
+        sqlx::query_as::<
+            _,
+            (
+                Vec<u8>,
+                Vec<u8>,
+                Vec<u8>,
+                u32,
+                Option<Vec<u8>>,
+                Option<Vec<u8>>,
+            ),
+        >(
+            "
+            SELECT listener, tx_hash, raw, chunks, song, fee FROM pending_payments
+            ORDER BY received_at, rowid;
+            ",
+        )
+        .fetch_all(&mut self.acquire().await?)
+        .await?
+        .into_iter()
+        .map(|(listener, tx_hash, raw, chunks, song, fee)| {
+            let song_and_fee = match (song, fee) {
+                (Some(song), Some(fee)) => Some((song.try_into()?, U256::from_big_endian(&fee))),
+                _ => None,
+            };
+            Ok((
+                Address::from_slice(&listener),
+                H256::from_slice(&tx_hash),
+                raw,
+                chunks,
+                song_and_fee,
+            ))
+        })
+        .collect()
+    }
+
+    /// Record a confirmed get-chunks transaction of a listener, with our fee for the chunks in
+    /// IOTA.
+    pub async fn add_earning(
+        &self,
+        tx_hash: H256,
+        song: &SongId,
+        listener: Address,
+        chunks: u32,
+        fee: u64,
+        block_number: Option<u64>,
+    ) -> eyre::Result<()> {
+        sqlx::query(
+            "
+            INSERT OR REPLACE INTO earnings (tx_hash, song, listener, chunks, fee, block_number)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6);
+            ",
+        )
+        .bind(tx_hash.as_bytes())
+        .bind(song.as_slice())
+        .bind(listener.as_bytes())
+        .bind(chunks)
+        .bind(fee as i64)
+        .bind(block_number.map(|number| number as i64))
+        .execute(&mut self.acquire().await?)
+        .await?;
+        Ok(())
+    }
+
+    /// Get the total earnings per song, as `(song, payments, chunks, fee)`.
+    pub async fn get_earnings_per_song(&self) -> eyre::Result<Vec<(SongId, u32, u64, u64)>> {
+        let rows = self.get_totals_grouped_by("earnings", "song").await?;
+        rows.into_iter()
+            .map(|(song, payments, chunks, fee)| Ok((song.try_into()?, payments, chunks, fee)))
+            .collect()
+    }
+
+    /// Get the total earnings per listener, as `(listener, payments, chunks, fee)`.
+    pub async fn get_earnings_per_listener(&self) -> eyre::Result<Vec<(Address, u32, u64, u64)>> {
+        let rows = self.get_totals_grouped_by("earnings", "listener").await?;
+        Ok(rows
+            .into_iter()
+            .map(|(listener, payments, chunks, fee)| {
+                (Address::from_slice(&listener), payments, chunks, fee)
+            })
+            .collect())
+    }
+
+    /// Get the total earnings per day, as `(YYYY-MM-DD, payments, chunks, fee)`.
+    pub async fn get_earnings_per_day(&self) -> eyre::Result<Vec<(String, u32, u64, u64)>> {
+        let rows = self
+            .get_totals_grouped_by("earnings", "date(earned_at)")
+            .await?;
+        rows.into_iter()
+            .map(|(day, payments, chunks, fee)| {
+                Ok((String::from_utf8(day)?, payments, chunks, fee))
+            })
+            .collect()
+    }
+
+    /// Sum the chunks and fees of the earnings- or spending-table grouped by the given
+    /// column-expression, ordered by the highest fee.
+    async fn get_totals_grouped_by(
+        &self,
+        table: &'static str,
+        group_by: &'static str,
+    ) -> eyre::Result<Vec<(Vec<u8>, u32, u64, u64)>> {
+        let query = format!(
+            "
+            SELECT CAST({group_by} AS BLOB), COUNT(*), SUM(chunks), SUM(fee) FROM {table}
+            GROUP BY {group_by} ORDER BY SUM(fee) DESC;
+            "
+        );
+        Ok(sqlx::query_as::<_, (Vec<u8>, u32, i64, i64)>(&query)
+            .fetch_all(&mut self.acquire().await?)
+            .await?
+            .into_iter()
+            .map(|(key, payments, chunks, fee)| (key, payments, chunks as u64, fee as u64))
+            .collect())
+    }
+
+    /// Record that a get-chunks transaction of a listener failed, with the kind of failure.
+    pub async fn add_payment_failure(
+        &self,
+        listener: Address,
+        tx_hash: H256,
+        kind: &str,
+        message: &str,
+    ) -> eyre::Result<()> {
+        sqlx::query(
+            "
+            INSERT OR REPLACE INTO payment_failures (tx_hash, listener, kind, message)
+            VALUES (?1, ?2, ?3, ?4);
+            ",
+        )
+        .bind(tx_hash.as_bytes())
+        .bind(listener.as_bytes())
+        .bind(kind)
+        .bind(message)
+        .execute(&mut self.acquire().await?)
+        .await?;
+        Ok(())
+    }
+
+    /// Get the failed transactions of listeners, as `(listener, tx_hash, kind, message)`.
+    pub async fn get_payment_failures(&self) -> eyre::Result<Vec<(Address, H256, String, String)>> {
+        Ok(sqlx::query_as::<_, (Vec<u8>, Vec<u8>, String, String)>(
+            "
+            SELECT listener, tx_hash, kind, message FROM payment_failures ORDER BY failed_at;
+            ",
+        )
+        .fetch_all(&mut self.acquire().await?)
+        .await?
+        .into_iter()
+        .map(|(listener, tx_hash, kind, message)| {
+            (
+                Address::from_slice(&listener),
+                H256::from_slice(&tx_hash),
+                kind,
+                message,
+            )
+        })
+        .collect())
+    }
+
+    /// Record a get-chunks transaction we signed to pay a distributor for chunks of a song, with
+    /// the price and fee of the chunks in IOTA. It starts with the status `signed`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_spending(
+        &self,
+        tx_hash: H256,
+        song: &SongId,
+        distributor: Address,
+        first_chunk: u32,
+        chunks: u32,
+        fee: u64,
+        nonce: u64,
+    ) -> eyre::Result<()> {
+        sqlx::query(
+            "
+            INSERT OR REPLACE INTO spending
+                (tx_hash, song, distributor, first_chunk, chunks, fee, nonce, status)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 'signed');
+            ",
+        )
+        .bind(tx_hash.as_bytes())
+        .bind(song.as_slice())
+        .bind(distributor.as_bytes())
+        .bind(first_chunk)
+        .bind(chunks)
+        .bind(fee as i64)
+        .bind(nonce as i64)
+        .execute(&mut self.acquire().await?)
+        .await?;
+        Ok(())
+    }
+
+    /// Update the status of a signed get-chunks transaction: `sent` once it has been sent to the
+    /// distributor, `served` or `failed` once the download of its chunks finished, and `mined` or
+    /// `reverted` once the distributor included a served transaction in a block.
+    pub async fn set_spending_status(&self, tx_hash: H256, status: &str) -> eyre::Result<()> {
+        sqlx::query(
+            "
+            UPDATE spending SET status = ?2 WHERE tx_hash = ?1;
+            ",
+        )
+        .bind(tx_hash.as_bytes())
+        .bind(status)
+        .execute(&mut self.acquire().await?)
         .await?;
+        Ok(())
+    }
+
+    /// Get the signed get-chunks transactions, as
+    /// `(tx_hash, song, distributor, first_chunk, chunks, fee, nonce, status)` in the order they
+    /// were signed.
+    #[allow(clippy::type_complexity)]
+    pub async fn get_spending(
+        &self,
+    ) -> eyre::Result<Vec<(H256, SongId, Address, u32, u32, u64, u64, String)>> {
+        sqlx::query_as::<_, (Vec<u8>, Vec<u8>, Vec<u8>, u32, u32, i64, i64, String)>(
+            "
+            SELECT tx_hash, song, distributor, first_chunk, chunks, fee, nonce, status
+            FROM spending ORDER BY signed_at, rowid;
+            ",
+        )
+        .fetch_all(&mut self.acquire().await?)
+        .await?
+        .into_iter()
+        .map(
+            |(tx_hash, song, distributor, first_chunk, chunks, fee, nonce, status)| {
+                Ok((
+                    H256::from_slice(&tx_hash),
+                    song.try_into()?,
+                    Address::from_slice(&distributor),
+                    first_chunk,
+                    chunks,
+                    fee as u64,
+                    nonce as u64,
+                    status,
+                ))
+            },
+        )
+        .collect()
+    }
+
+    /// Get the total spending per song, as `(song, payments, chunks, fee)`.
+    pub async fn get_spending_per_song(&self) -> eyre::Result<Vec<(SongId, u32, u64, u64)>> {
+        let rows = self.get_totals_grouped_by("spending", "song").await?;
+        rows.into_iter()
+            .map(|(song, payments, chunks, fee)| Ok((song.try_into()?, payments, chunks, fee)))
+            .collect()
+    }
+
+    /// Get the total spending per distributor, as `(distributor, payments, chunks, fee)`.
+    pub async fn get_spending_per_distributor(
+        &self,
+    ) -> eyre::Result<Vec<(Address, u32, u64, u64)>> {
+        let rows = self
+            .get_totals_grouped_by("spending", "distributor")
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(distributor, payments, chunks, fee)| {
+                (Address::from_slice(&distributor), payments, chunks, fee)
+            })
+            .collect())
+    }
+
+    /// Get the total spending per day, as `(YYYY-MM-DD, payments, chunks, fee)`.
+    pub async fn get_spending_per_day(&self) -> eyre::Result<Vec<(String, u32, u64, u64)>> {
+        let rows = self
+            .get_totals_grouped_by("spending", "date(signed_at)")
+            .await?;
+        rows.into_iter()
+            .map(|(day, payments, chunks, fee)| {
+                Ok((String::from_utf8(day)?, payments, chunks, fee))
+            })
+            .collect()
+    }
 
-        Ok(row.0)
+    /// Get the total spending per status, as `(status, payments, chunks, fee)`.
+    pub async fn get_spending_per_status(&self) -> eyre::Result<Vec<(String, u32, u64, u64)>> {
+        let rows = self.get_totals_grouped_by("spending", "status").await?;
+        rows.into_iter()
+            .map(|(status, payments, chunks, fee)| {
+                Ok((String::from_utf8(status)?, payments, chunks, fee))
+            })
+            .collect()
     }
 
     pub async fn remove_private_key(&self) -> eyre::Result<()> {
@@ -300,6 +899,24 @@ impl Database {
     }
 }
 
+/// Insert the song as a row per chunk, with the keccak256-hash of the chunk.
+async fn insert_chunks(conn: &mut SqliteConnection, id: &[u8], data: &[u8]) -> eyre::Result<()> {
+    for (index, chunk) in data.chunks(BYTES_PER_CHUNK as usize).enumerate() {
+        sqlx::query(
+            "
+            INSERT INTO song_chunks (song, idx, data, hash) VALUES (?1, ?2, ?3, ?4);
+            ",
+        )
+        .bind(id)
+        .bind(index as u32)
+        .bind(chunk)
+        .bind(&keccak256(chunk)[..])
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use crate::{test, BYTES_PER_CHUNK_USIZE};
@@ -338,6 +955,28 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn song_length() -> eyre::Result<()> {
+        let song_id = SongId::try_from_hex(test::HEX_ID_1).unwrap();
+        let db = Database::initialize_in_memory().await?;
+        assert_eq!(db.get_chunk_count(&song_id).await?, None);
+
+        let song_data = std::fs::read(
+            "mp3/0x0800000722040506080000072204050608000007220405060800000722040506.mp3",
+        )?;
+        db.add_song(&song_id, &song_data).await?;
+
+        assert_eq!(
+            db.get_song_len(&song_id).await?,
+            Some(song_data.len() as u32)
+        );
+        let chunks = db.get_chunk_count(&song_id).await?.unwrap() as usize;
+        assert!(chunks * BYTES_PER_CHUNK_USIZE >= song_data.len());
+        assert!((chunks - 1) * BYTES_PER_CHUNK_USIZE < song_data.len());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn add_remove_song() -> eyre::Result<()> {
         let unvalidated_song_id = SongId::try_from_hex(test::HEX_ID_1).unwrap();
@@ -358,6 +997,117 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn song_blobs_are_migrated_to_chunks() -> eyre::Result<()> {
+        let song_id = SongId::try_from_hex(test::HEX_ID_1).unwrap();
+        let db = Database::initialize_in_memory().await?;
+        let song_data = std::fs::read(
+            "mp3/0x0800000722040506080000072204050608000007220405060800000722040506.mp3",
+        )?;
+
+        // The schema of older versions, with a single blob per song
+        sqlx::query(
+            "
+            DROP TABLE songs;
+            CREATE TABLE songs (
+                id BLOB PRIMARY KEY,
+                data BLOB NOT NULL,
+                inserted_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
+            );
+            INSERT INTO songs (id, data) VALUES (?1, ?2);
+            ",
+        )
+        .bind(song_id.as_slice())
+        .bind(&song_data)
+        .execute(&mut db.acquire().await?)
+        .await?;
+
+        db.migrate_db().await?;
+        assert_eq!(db.get_all_downloaded_song_ids().await?, vec![song_id]);
+        assert_eq!(
+            db.get_song_len(&song_id).await?,
+            Some(song_data.len() as u32)
+        );
+        assert_eq!(db.get_chunks(&song_id, 0, 100).await?, song_data);
+        assert!(db.get_corrupt_chunks(&song_id).await?.is_empty());
+
+        // Migrating again does nothing
+        db.migrate_db().await?;
+        assert_eq!(db.get_chunks(&song_id, 0, 100).await?, song_data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_payments_are_migrated() -> eyre::Result<()> {
+        let db = Database::initialize_in_memory().await?;
+        let (listener, tx_hash) = (Address::random(), H256::random());
+
+        // The schema of older versions, without the song and fee
+        sqlx::query(
+            "
+            DROP TABLE pending_payments;
+            CREATE TABLE pending_payments (
+                tx_hash BLOB PRIMARY KEY,
+                listener BLOB NOT NULL,
+                raw BLOB NOT NULL,
+                chunks INT NOT NULL,
+                received_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
+            );
+            INSERT INTO pending_payments (tx_hash, listener, raw, chunks) VALUES (?1, ?2, ?3, 10);
+            ",
+        )
+        .bind(tx_hash.as_bytes())
+        .bind(listener.as_bytes())
+        .bind([1_u8, 2, 3].as_slice())
+        .execute(&mut db.acquire().await?)
+        .await?;
+
+        db.migrate_db().await?;
+        assert_eq!(
+            db.get_pending_payments().await?,
+            vec![(listener, tx_hash, vec![1, 2, 3], 10, None)]
+        );
+
+        // Migrating again does nothing
+        db.migrate_db().await?;
+        assert_eq!(db.get_pending_payments().await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_chunks_are_found() -> eyre::Result<()> {
+        let song_id = SongId::try_from_hex(test::HEX_ID_1).unwrap();
+        let db = Database::initialize_in_memory().await?;
+        assert!(db.get_corrupt_chunks(&song_id).await.is_err());
+
+        let song_data = std::fs::read(
+            "mp3/0x0800000722040506080000072204050608000007220405060800000722040506.mp3",
+        )?;
+        db.add_song(&song_id, &song_data).await?;
+        assert!(db.get_corrupt_chunks(&song_id).await?.is_empty());
+
+        sqlx::query(
+            "
+            UPDATE song_chunks SET data = zeroblob(10) WHERE song = ?1 AND idx = 3;
+            DELETE FROM song_chunks WHERE song = ?1 AND idx = 7;
+            ",
+        )
+        .bind(song_id.as_slice())
+        .execute(&mut db.acquire().await?)
+        .await?;
+
+        assert_eq!(db.get_corrupt_chunks(&song_id).await?, vec![3, 7]);
+        assert!(db.get_chunks(&song_id, 5, 5).await.is_err());
+        assert_eq!(
+            db.get_chunks(&song_id, 8, 2).await?,
+            song_data[8 * BYTES_PER_CHUNK_USIZE..10 * BYTES_PER_CHUNK_USIZE]
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn song_index() -> eyre::Result<()> {
         let unvalidated_song_id = SongId::try_from_hex(test::HEX_ID_1).unwrap();
@@ -414,4 +1164,126 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn listener_debt() -> eyre::Result<()> {
+        let db = Database::initialize_in_memory().await?;
+        let address = Address::random();
+
+        db.set_listener_debt(address, 5).await?;
+        db.set_listener_debt(address, -3).await?;
+        assert_eq!(db.get_listener_debts().await?, vec![(address, -3)]);
+        db.set_listener_debt(address, 0).await?;
+        assert_eq!(db.get_listener_debts().await?, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_payments() -> eyre::Result<()> {
+        let db = Database::initialize_in_memory().await?;
+        let (listener, tx_hash) = (Address::random(), H256::random());
+
+        let song = SongId::try_from_hex(test::HEX_ID_1)?;
+        let fee = U256::from(u128::MAX) * 3;
+
+        db.add_pending_payment(listener, tx_hash, &[1, 2, 3], &song, 10, fee)
+            .await?;
+        assert_eq!(
+            db.get_pending_payments().await?,
+            vec![(listener, tx_hash, vec![1, 2, 3], 10, Some((song, fee)))]
+        );
+        db.remove_pending_payment(tx_hash).await?;
+        assert_eq!(db.get_pending_payments().await?, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn earnings_are_grouped() -> eyre::Result<()> {
+        let db = Database::initialize_in_memory().await?;
+        let song1 = SongId::try_from_hex(test::HEX_ID_1)?;
+        let song2 = SongId::try_from_hex(test::HEX_ID_2)?;
+        let (listener1, listener2) = (Address::random(), Address::random());
+
+        db.add_earning(H256::random(), &song1, listener1, 10, 2500, Some(1))
+            .await?;
+        db.add_earning(H256::random(), &song1, listener2, 5, 1250, Some(2))
+            .await?;
+        db.add_earning(H256::random(), &song2, listener2, 10, 5000, None)
+            .await?;
+
+        assert_eq!(
+            db.get_earnings_per_song().await?,
+            vec![(song2, 1, 10, 5000), (song1, 2, 15, 3750)]
+        );
+        assert_eq!(
+            db.get_earnings_per_listener().await?,
+            vec![(listener2, 2, 15, 6250), (listener1, 1, 10, 2500)]
+        );
+        let per_day = db.get_earnings_per_day().await?;
+        assert_eq!(per_day.len(), 1);
+        assert_eq!((per_day[0].1, per_day[0].2, per_day[0].3), (3, 25, 8750));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn payment_failures() -> eyre::Result<()> {
+        let db = Database::initialize_in_memory().await?;
+        let (listener, tx_hash) = (Address::random(), H256::random());
+
+        db.add_payment_failure(listener, tx_hash, "dropped", "Dropped")
+            .await?;
+        db.add_payment_failure(listener, tx_hash, "reverted", "Reverted")
+            .await?;
+        assert_eq!(
+            db.get_payment_failures().await?,
+            vec![(listener, tx_hash, "reverted".into(), "Reverted".into())]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spending_is_recorded() -> eyre::Result<()> {
+        let db = Database::initialize_in_memory().await?;
+        let song1 = SongId::try_from_hex(test::HEX_ID_1)?;
+        let song2 = SongId::try_from_hex(test::HEX_ID_2)?;
+        let distributor = Address::random();
+        let (tx1, tx2, tx3) = (H256::random(), H256::random(), H256::random());
+
+        db.add_spending(tx1, &song1, distributor, 0, 20, 400, 0)
+            .await?;
+        db.add_spending(tx2, &song1, distributor, 20, 5, 100, 1)
+            .await?;
+        db.add_spending(tx3, &song2, distributor, 0, 20, 1000, 2)
+            .await?;
+        db.set_spending_status(tx1, "served").await?;
+        db.set_spending_status(tx2, "failed").await?;
+
+        let spending = db.get_spending().await?;
+        assert_eq!(
+            spending[1],
+            (tx2, song1, distributor, 20, 5, 100, 1, "failed".to_string())
+        );
+        assert_eq!(spending[2].7, "signed");
+        assert_eq!(
+            db.get_spending_per_song().await?,
+            vec![(song2, 1, 20, 1000), (song1, 2, 25, 500)]
+        );
+        assert_eq!(
+            db.get_spending_per_distributor().await?,
+            vec![(distributor, 3, 45, 1500)]
+        );
+        assert_eq!(
+            db.get_spending_per_status().await?,
+            vec![
+                ("signed".to_string(), 1, 20, 1000),
+                ("served".to_string(), 1, 20, 400),
+                ("failed".to_string(), 1, 5, 100)
+            ]
+        );
+        assert_eq!(db.get_spending_per_day().await?.len(), 1);
+        Ok(())
+    }
 }
@@ -0,0 +1,115 @@
+use super::tcp::{DEFAULT_MAX_REQUEST_LEN, DEFAULT_MAX_RESPONSE_LEN};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The `[connections]` table of the config-file, with all durations in seconds and bandwidths in
+/// bytes per second.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ConnectionsConfig {
+    pub idle_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub write_timeout: Option<u64>,
+    pub max_debt_duration: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_connections_per_wallet: Option<usize>,
+    pub bandwidth: Option<u64>,
+    pub bandwidth_per_connection: Option<u64>,
+    pub shutdown_timeout: Option<u64>,
+    pub max_request_len: Option<u32>,
+    pub max_response_len: Option<u32>,
+}
+
+/// The limits on the connections of listeners with the distributor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// How long a connection may stay open without requests, chunks or pending transactions.
+    pub idle_timeout: Duration,
+    /// How long a listener may take to send a frame, once the first bytes have arrived.
+    pub read_timeout: Duration,
+    /// How long a single frame may take to be written to the listener.
+    pub write_timeout: Duration,
+    /// How long a connection may stay in debt without any of its transactions confirming.
+    pub max_debt_duration: Duration,
+    /// How many connections may be open at once.
+    pub max_connections: usize,
+    /// How many connections may be open at once from a single ip-address.
+    pub max_connections_per_ip: usize,
+    /// How many connections may be open at once that request chunks for a single wallet.
+    pub max_connections_per_wallet: usize,
+    /// How many bytes per second may be sent across all connections, if limited.
+    pub bandwidth: Option<u64>,
+    /// How many bytes per second may be sent over a single connection, if limited.
+    pub bandwidth_per_connection: Option<u64>,
+    /// How long open connections may take to finish their requests and payments when the
+    /// distributor shuts down.
+    pub shutdown_timeout: Duration,
+    /// The maximum body-length in bytes of a frame sent by a listener.
+    pub max_request_len: u32,
+    /// The maximum body-length in bytes of a frame sent by a distributor we download from.
+    pub max_response_len: u32,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(60),
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            max_debt_duration: Duration::from_secs(120),
+            max_connections: 256,
+            max_connections_per_ip: 8,
+            max_connections_per_wallet: 4,
+            bandwidth: None,
+            bandwidth_per_connection: None,
+            shutdown_timeout: Duration::from_secs(30),
+            max_request_len: DEFAULT_MAX_REQUEST_LEN,
+            max_response_len: DEFAULT_MAX_RESPONSE_LEN,
+        }
+    }
+}
+
+impl From<ConnectionsConfig> for ConnectionLimits {
+    fn from(config: ConnectionsConfig) -> Self {
+        let default = Self::default();
+        let secs_or = |secs: Option<u64>, default| secs.map(Duration::from_secs).unwrap_or(default);
+        Self {
+            idle_timeout: secs_or(config.idle_timeout, default.idle_timeout),
+            read_timeout: secs_or(config.read_timeout, default.read_timeout),
+            write_timeout: secs_or(config.write_timeout, default.write_timeout),
+            max_debt_duration: secs_or(config.max_debt_duration, default.max_debt_duration),
+            max_connections: config.max_connections.unwrap_or(default.max_connections),
+            max_connections_per_ip: config
+                .max_connections_per_ip
+                .unwrap_or(default.max_connections_per_ip),
+            max_connections_per_wallet: config
+                .max_connections_per_wallet
+                .unwrap_or(default.max_connections_per_wallet),
+            bandwidth: config.bandwidth.filter(|bytes| *bytes > 0),
+            bandwidth_per_connection: config.bandwidth_per_connection.filter(|bytes| *bytes > 0),
+            shutdown_timeout: secs_or(config.shutdown_timeout, default.shutdown_timeout),
+            max_request_len: config.max_request_len.unwrap_or(default.max_request_len),
+            max_response_len: config.max_response_len.unwrap_or(default.max_response_len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_limits_use_defaults() {
+        let config: ConnectionsConfig =
+            toml::from_str("idle_timeout = 5\nmax_request_len = 2048").unwrap();
+        let limits = ConnectionLimits::from(config);
+        assert_eq!(limits.idle_timeout, Duration::from_secs(5));
+        assert_eq!(
+            limits.read_timeout,
+            ConnectionLimits::default().read_timeout
+        );
+        assert_eq!(limits.shutdown_timeout, Duration::from_secs(30));
+        assert_eq!(limits.max_request_len, 2048);
+        assert_eq!(limits.max_response_len, DEFAULT_MAX_RESPONSE_LEN);
+    }
+}
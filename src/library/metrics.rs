@@ -0,0 +1,401 @@
+use crate::library::util::SongId;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// The metrics of this process, which are recorded by the connections, transaction-pools and
+/// client.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// The upper bounds of the buckets of the rpc-latency histogram, in seconds.
+const LATENCY_BUCKETS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A histogram of the latency of a single rpc-method.
+#[derive(Debug, Default)]
+struct Latency {
+    /// The amount of requests per bucket, not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Metrics of a running distributor, which are exported in the Prometheus text-format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    active_connections: AtomicI64,
+    pending_transactions: AtomicI64,
+    chunk_cache_hits: AtomicU64,
+    chunk_cache_misses: AtomicU64,
+    chunk_cache_bytes: AtomicU64,
+    server_registered: AtomicBool,
+    /// The chunks and bytes served per song.
+    served: Mutex<HashMap<SongId, (u64, u64)>>,
+    /// Whether we are registered as distributor of the song.
+    registered_songs: Mutex<HashMap<SongId, bool>>,
+    /// The settled payments per status.
+    payments: Mutex<BTreeMap<&'static str, u64>>,
+    /// The latency of rpc-requests per method.
+    rpc_latency: Mutex<BTreeMap<String, Latency>>,
+}
+
+/// An open connection, which is counted as active until it is dropped.
+#[derive(Debug)]
+#[must_use]
+pub struct ActiveConnection<'a>(&'a Metrics);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Count a connection as active until the returned guard is dropped.
+    pub fn open_connection(&self) -> ActiveConnection<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self)
+    }
+
+    pub fn add_chunks_served(&self, song: SongId, chunks: u32, bytes: usize) {
+        let mut served = self.served.lock().unwrap();
+        let (total_chunks, total_bytes) = served.entry(song).or_default();
+        *total_chunks += chunks as u64;
+        *total_bytes += bytes as u64;
+    }
+
+    /// Count the chunks that were looked up in the chunk-cache, by whether they were cached.
+    pub fn add_chunk_cache_lookups(&self, hits: u64, misses: u64) {
+        self.chunk_cache_hits.fetch_add(hits, Ordering::Relaxed);
+        self.chunk_cache_misses.fetch_add(misses, Ordering::Relaxed);
+    }
+
+    pub fn set_chunk_cache_bytes(&self, bytes: usize) {
+        self.chunk_cache_bytes
+            .store(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Add to the amount of transactions waiting in transaction-pools, which is negative for
+    /// transactions that left a pool.
+    pub fn add_pending_transactions(&self, amount: i64) {
+        self.pending_transactions
+            .fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Count a settled payment, with status `confirmed` or the kind of failure.
+    pub fn add_payment(&self, status: &'static str) {
+        *self.payments.lock().unwrap().entry(status).or_default() += 1;
+    }
+
+    pub fn set_server_registered(&self, registered: bool) {
+        self.server_registered.store(registered, Ordering::Relaxed);
+    }
+
+    pub fn set_songs_registered(&self, songs: &[SongId], registered: bool) {
+        let mut registered_songs = self.registered_songs.lock().unwrap();
+        for song in songs {
+            registered_songs.insert(*song, registered);
+        }
+    }
+
+    pub fn observe_rpc_latency(&self, method: &str, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let mut rpc_latency = self.rpc_latency.lock().unwrap();
+        let latency = match rpc_latency.get_mut(method) {
+            Some(latency) => latency,
+            None => rpc_latency.entry(method.to_string()).or_default(),
+        };
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            latency.buckets[bucket] += 1;
+        }
+        latency.count += 1;
+        latency.sum += seconds;
+    }
+
+    /// Render all metrics in the Prometheus text-format, together with the outstanding credit
+    /// in chunks which is kept by the distributor itself.
+    pub fn render(&self, outstanding_credit: i64) -> String {
+        let mut out = String::new();
+        let header = |out: &mut String, name: &str, kind: &str, help: &str| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+        };
+
+        header(
+            &mut out,
+            "tangle_tunes_active_connections",
+            "gauge",
+            "The connections with listeners that are open.",
+        );
+        let active_connections = self.active_connections.load(Ordering::Relaxed);
+        let _ = writeln!(out, "tangle_tunes_active_connections {active_connections}");
+
+        let served = self.served.lock().unwrap();
+        let mut served: Vec<_> = served.iter().collect();
+        served.sort();
+        header(
+            &mut out,
+            "tangle_tunes_chunks_served_total",
+            "counter",
+            "The chunks sent to listeners per song.",
+        );
+        for (song, (chunks, _)) in &served {
+            let _ = writeln!(
+                out,
+                "tangle_tunes_chunks_served_total{{song=\"{song}\"}} {chunks}"
+            );
+        }
+        header(
+            &mut out,
+            "tangle_tunes_bytes_served_total",
+            "counter",
+            "The bytes of chunks sent to listeners per song.",
+        );
+        for (song, (_, bytes)) in &served {
+            let _ = writeln!(
+                out,
+                "tangle_tunes_bytes_served_total{{song=\"{song}\"}} {bytes}"
+            );
+        }
+
+        header(
+            &mut out,
+            "tangle_tunes_chunk_cache_lookups_total",
+            "counter",
+            "The chunks looked up in the chunk-cache, by whether they were cached.",
+        );
+        let hits = self.chunk_cache_hits.load(Ordering::Relaxed);
+        let misses = self.chunk_cache_misses.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "tangle_tunes_chunk_cache_lookups_total{{result=\"hit\"}} {hits}"
+        );
+        let _ = writeln!(
+            out,
+            "tangle_tunes_chunk_cache_lookups_total{{result=\"miss\"}} {misses}"
+        );
+        header(
+            &mut out,
+            "tangle_tunes_chunk_cache_bytes",
+            "gauge",
+            "The bytes of chunks held in the chunk-cache.",
+        );
+        let chunk_cache_bytes = self.chunk_cache_bytes.load(Ordering::Relaxed);
+        let _ = writeln!(out, "tangle_tunes_chunk_cache_bytes {chunk_cache_bytes}");
+
+        header(
+            &mut out,
+            "tangle_tunes_outstanding_credit_chunks",
+            "gauge",
+            "The chunks sent to listeners that have not been paid for.",
+        );
+        let _ = writeln!(
+            out,
+            "tangle_tunes_outstanding_credit_chunks {outstanding_credit}"
+        );
+
+        header(
+            &mut out,
+            "tangle_tunes_pending_transactions",
+            "gauge",
+            "The transactions of listeners waiting in transaction-pools.",
+        );
+        let pending_transactions = self.pending_transactions.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "tangle_tunes_pending_transactions {pending_transactions}"
+        );
+
+        header(
+            &mut out,
+            "tangle_tunes_payments_total",
+            "counter",
+            "The settled payments of listeners, by status.",
+        );
+        for (status, count) in self.payments.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "tangle_tunes_payments_total{{status=\"{status}\"}} {count}"
+            );
+        }
+
+        header(
+            &mut out,
+            "tangle_tunes_server_registered",
+            "gauge",
+            "Whether our server-address is registered on the smart-contract.",
+        );
+        let server_registered = self.server_registered.load(Ordering::Relaxed) as u8;
+        let _ = writeln!(out, "tangle_tunes_server_registered {server_registered}");
+
+        let registered_songs = self.registered_songs.lock().unwrap();
+        let mut registered_songs: Vec<_> = registered_songs.iter().collect();
+        registered_songs.sort();
+        header(
+            &mut out,
+            "tangle_tunes_song_registered",
+            "gauge",
+            "Whether we are registered as distributor of the song.",
+        );
+        for (song, registered) in registered_songs {
+            let registered = *registered as u8;
+            let _ = writeln!(
+                out,
+                "tangle_tunes_song_registered{{song=\"{song}\"}} {registered}"
+            );
+        }
+
+        header(
+            &mut out,
+            "tangle_tunes_rpc_latency_seconds",
+            "histogram",
+            "The latency of requests to the node, by rpc-method.",
+        );
+        for (method, latency) in self.rpc_latency.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "tangle_tunes_rpc_latency_seconds_bucket{{method=\"{method}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let (count, sum) = (latency.count, latency.sum);
+            let _ = writeln!(
+                out,
+                "tangle_tunes_rpc_latency_seconds_bucket{{method=\"{method}\",le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(
+                out,
+                "tangle_tunes_rpc_latency_seconds_sum{{method=\"{method}\"}} {sum}"
+            );
+            let _ = writeln!(
+                out,
+                "tangle_tunes_rpc_latency_seconds_count{{method=\"{method}\"}} {count}"
+            );
+        }
+
+        out
+    }
+}
+
+/// Serve the metrics on `/metrics` at the given address. The outstanding credit is looked up
+/// for every request.
+pub async fn serve(
+    address: SocketAddr,
+    outstanding_credit: impl Fn() -> i64 + Copy + Send + Sync + 'static,
+) -> eyre::Result<()> {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+            Ok::<_, Infallible>(respond(&request, outstanding_credit()))
+        }))
+    });
+
+    println!("Serving metrics on http://{address}/metrics");
+    Server::try_bind(&address)?.serve(make_service).await?;
+    Ok(())
+}
+
+fn respond(request: &Request<Body>, outstanding_credit: i64) -> Response<Body> {
+    let mut response = Response::default();
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+            *response.body_mut() = METRICS.render(outstanding_credit).into();
+        }
+        _ => *response.status_mut() = StatusCode::NOT_FOUND,
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test;
+
+    #[test]
+    fn metrics_are_rendered() -> eyre::Result<()> {
+        let metrics = Metrics::default();
+        let song = SongId::try_from_hex(test::HEX_ID_1)?;
+
+        let connection = metrics.open_connection();
+        metrics.add_chunks_served(song, 2, 65536);
+        metrics.add_chunks_served(song, 1, 100);
+        metrics.add_pending_transactions(3);
+        metrics.add_pending_transactions(-1);
+        metrics.add_chunk_cache_lookups(3, 1);
+        metrics.set_chunk_cache_bytes(65000);
+        metrics.add_payment("confirmed");
+        metrics.add_payment("reverted");
+        metrics.add_payment("confirmed");
+        metrics.set_server_registered(true);
+        metrics.set_songs_registered(&[song], true);
+        metrics.observe_rpc_latency("eth_call", Duration::from_millis(75));
+        metrics.observe_rpc_latency("eth_call", Duration::from_secs(20));
+
+        let rendered = metrics.render(12);
+        let has_line = |line: &str| rendered.lines().any(|rendered| rendered == line);
+        assert!(has_line("tangle_tunes_active_connections 1"));
+        drop(connection);
+        assert!(metrics
+            .render(0)
+            .contains("tangle_tunes_active_connections 0\n"));
+
+        assert!(has_line(&format!(
+            "tangle_tunes_chunks_served_total{{song=\"{song}\"}} 3"
+        )));
+        assert!(has_line(&format!(
+            "tangle_tunes_bytes_served_total{{song=\"{song}\"}} 65636"
+        )));
+        assert!(has_line(
+            "tangle_tunes_chunk_cache_lookups_total{result=\"hit\"} 3"
+        ));
+        assert!(has_line(
+            "tangle_tunes_chunk_cache_lookups_total{result=\"miss\"} 1"
+        ));
+        assert!(has_line("tangle_tunes_chunk_cache_bytes 65000"));
+        assert!(has_line("tangle_tunes_outstanding_credit_chunks 12"));
+        assert!(has_line("tangle_tunes_pending_transactions 2"));
+        assert!(has_line(
+            "tangle_tunes_payments_total{status=\"confirmed\"} 2"
+        ));
+        assert!(has_line(
+            "tangle_tunes_payments_total{status=\"reverted\"} 1"
+        ));
+        assert!(has_line("tangle_tunes_server_registered 1"));
+        assert!(has_line(&format!(
+            "tangle_tunes_song_registered{{song=\"{song}\"}} 1"
+        )));
+        assert!(has_line(
+            "tangle_tunes_rpc_latency_seconds_bucket{method=\"eth_call\",le=\"0.05\"} 0"
+        ));
+        assert!(has_line(
+            "tangle_tunes_rpc_latency_seconds_bucket{method=\"eth_call\",le=\"0.1\"} 1"
+        ));
+        assert!(has_line(
+            "tangle_tunes_rpc_latency_seconds_bucket{method=\"eth_call\",le=\"10\"} 1"
+        ));
+        assert!(has_line(
+            "tangle_tunes_rpc_latency_seconds_bucket{method=\"eth_call\",le=\"+Inf\"} 2"
+        ));
+        assert!(has_line(
+            "tangle_tunes_rpc_latency_seconds_count{method=\"eth_call\"} 2"
+        ));
+        Ok(())
+    }
+}
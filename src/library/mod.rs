@@ -1,8 +1,13 @@
 pub mod abi;
 pub mod app;
+pub mod chunk_cache;
 pub mod client;
 pub mod crypto;
 pub mod database;
+pub mod limits;
+pub mod metrics;
+pub mod storage;
 pub mod tcp;
+pub mod tls;
 pub mod transaction_pool;
 pub mod util;
@@ -0,0 +1,160 @@
+use super::SongStorage;
+use crate::{library::util::SongId, BYTES_PER_CHUNK};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use eyre::Context;
+use std::{
+    fs::File,
+    io::{ErrorKind, Write},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::task::spawn_blocking;
+
+/// Distinguishes the temporary files of songs that are being stored.
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Stores songs as `<SONG_ID>.mp3` files in a directory. Ranges of chunks are read with
+/// positioned reads, so a song is never read as a whole. No hashes of chunks are kept.
+#[derive(Debug, Clone)]
+pub struct FilesystemStorage {
+    directory: PathBuf,
+}
+
+impl FilesystemStorage {
+    /// Open the storage in the directory, which is created if it does not exist.
+    pub fn open(directory: PathBuf) -> eyre::Result<Self> {
+        std::fs::create_dir_all(&directory).wrap_err(format!(
+            "Could not create song-directory {}",
+            directory.display()
+        ))?;
+        Ok(Self { directory })
+    }
+
+    fn path(&self, id: &SongId) -> PathBuf {
+        self.directory.join(format!("{id}.mp3"))
+    }
+
+    /// The songs in the directory, with the time they were stored.
+    async fn entries(&self) -> eyre::Result<Vec<(SongId, DateTime<Utc>)>> {
+        let directory = self.directory.clone();
+        spawn_blocking(move || {
+            let mut songs = Vec::new();
+            for entry in std::fs::read_dir(directory)? {
+                let entry = entry?;
+                let path = entry.path();
+                if path.extension() != Some("mp3".as_ref()) {
+                    continue;
+                }
+                let Some(Ok(id)) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .map(SongId::try_from_hex)
+                else {
+                    continue;
+                };
+                songs.push((id, entry.metadata()?.modified()?.into()));
+            }
+            Ok(songs)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl SongStorage for FilesystemStorage {
+    async fn put(&self, id: &SongId, data: &[u8]) -> eyre::Result<()> {
+        let path = self.path(id);
+        // The song is written to a temporary file first, so that it is never listed half-written.
+        // Every put has its own temporary file, so concurrent puts of a song do not mix.
+        let temp_path = self.directory.join(format!(
+            ".{id}.mp3.{}-{}.tmp",
+            std::process::id(),
+            NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let data = data.to_vec();
+        spawn_blocking(move || {
+            // Unlike a rename, the hard-link fails if the song exists, so only one put succeeds.
+            let linked = File::create(&temp_path)
+                .and_then(|mut file| {
+                    file.write_all(&data)?;
+                    file.sync_all()
+                })
+                .and_then(|()| std::fs::hard_link(&temp_path, &path));
+            let _ = std::fs::remove_file(&temp_path);
+            match linked {
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    bail!("Song {} is already stored", path.display())
+                }
+                linked => Ok(linked?),
+            }
+        })
+        .await?
+    }
+
+    async fn get_chunks(
+        &self,
+        id: &SongId,
+        chunk_start: u32,
+        chunks: u32,
+    ) -> eyre::Result<Vec<u8>> {
+        let path = self.path(id);
+        let id = *id;
+        spawn_blocking(move || {
+            let file = File::open(&path).wrap_err(format!("Song {id} is not stored"))?;
+            let mut offset = chunk_start as u64 * BYTES_PER_CHUNK as u64;
+            let mut data = vec![0; chunks as usize * BYTES_PER_CHUNK as usize];
+            let mut read = 0;
+            while read < data.len() {
+                match file.read_at(&mut data[read..], offset) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        read += n;
+                        offset += n as u64;
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            data.truncate(read);
+            Ok(data)
+        })
+        .await?
+    }
+
+    async fn delete(&self, id: &SongId) -> eyre::Result<bool> {
+        match tokio::fs::remove_file(self.path(id)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self) -> eyre::Result<Vec<SongId>> {
+        Ok(self
+            .entries()
+            .await?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    async fn list_since(&self, since: &DateTime<Utc>) -> eyre::Result<Vec<SongId>> {
+        Ok(self
+            .entries()
+            .await?
+            .into_iter()
+            .filter(|(_, stored_at)| stored_at >= since)
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    async fn size(&self, id: &SongId) -> eyre::Result<Option<u32>> {
+        match tokio::fs::metadata(self.path(id)).await {
+            Ok(metadata) => Ok(Some(metadata.len().try_into()?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{
+    library::{database::Database, util::SongId},
+    BYTES_PER_CHUNK,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use num_integer::div_ceil;
+use std::{fmt::Debug, path::PathBuf};
+
+mod filesystem;
+mod sqlite;
+
+pub use filesystem::FilesystemStorage;
+pub use sqlite::SqliteStorage;
+
+/// Where the mp3-data of songs is stored, configured with the `[storage]` table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StorageSettings {
+    /// In the database, with a row per chunk in the `song_chunks` table.
+    #[default]
+    Sqlite,
+    /// As `<SONG_ID>.mp3` files in the directory.
+    Filesystem { directory: PathBuf },
+}
+
+impl StorageSettings {
+    /// Open the storage, which is kept in the database for the sqlite-backend.
+    pub fn open(&self, database: Database) -> eyre::Result<Box<dyn SongStorage>> {
+        Ok(match self {
+            StorageSettings::Sqlite => Box::new(SqliteStorage::new(database)),
+            StorageSettings::Filesystem { directory } => {
+                Box::new(FilesystemStorage::open(directory.clone())?)
+            }
+        })
+    }
+}
+
+/// The storage of the mp3-data of the songs we distribute.
+#[async_trait]
+pub trait SongStorage: Debug + Send + Sync {
+    /// Store a song, which fails if it is already stored.
+    async fn put(&self, id: &SongId, data: &[u8]) -> eyre::Result<()>;
+
+    /// Get the chunks from (chunk_start, chunk_start + chunks), which stop early at the end of
+    /// the song. Fails if the song is not stored.
+    async fn get_chunks(&self, id: &SongId, chunk_start: u32, chunks: u32)
+        -> eyre::Result<Vec<u8>>;
+
+    /// Remove a song, and return whether it was stored.
+    async fn delete(&self, id: &SongId) -> eyre::Result<bool>;
+
+    /// All songs that are stored.
+    async fn list(&self) -> eyre::Result<Vec<SongId>>;
+
+    /// The songs that were stored since the given time.
+    async fn list_since(&self, since: &DateTime<Utc>) -> eyre::Result<Vec<SongId>>;
+
+    /// The length of the song in bytes, or `None` if the song is not stored.
+    async fn size(&self, id: &SongId) -> eyre::Result<Option<u32>>;
+
+    /// The indices of the chunks of the song that are missing or do not match their hash. Fails
+    /// if the song is not stored, or if the backend keeps no hashes.
+    async fn corrupt_chunks(&self, _id: &SongId) -> eyre::Result<Vec<u32>> {
+        Err(eyre!("The storage-backend keeps no hashes of chunks"))
+    }
+
+    /// The amount of chunks of the song, or `None` if the song is not stored.
+    async fn chunk_count(&self, id: &SongId) -> eyre::Result<Option<u32>> {
+        Ok(self
+            .size(id)
+            .await?
+            .map(|len| div_ceil(len, BYTES_PER_CHUNK)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test;
+
+    /// Every backend must behave the same as the sqlite-backend.
+    async fn storage_behaves_like_sqlite(storage: &dyn SongStorage) -> eyre::Result<()> {
+        let song = SongId::try_from_hex(test::HEX_ID_1)?;
+        let data = std::fs::read(format!("mp3/{}.mp3", test::HEX_ID_1))?;
+        let before = Utc::now() - chrono::Duration::seconds(1);
+
+        assert_eq!(storage.list().await?, vec![]);
+        assert_eq!(storage.size(&song).await?, None);
+        assert!(storage.get_chunks(&song, 0, 1).await.is_err());
+
+        storage.put(&song, &data).await?;
+        assert!(storage.put(&song, &data).await.is_err());
+        assert_eq!(storage.list().await?, vec![song]);
+        assert_eq!(storage.list_since(&before).await?, vec![song]);
+        assert_eq!(storage.size(&song).await?, Some(data.len() as u32));
+        assert_eq!(
+            storage.chunk_count(&song).await?,
+            Some(div_ceil(data.len() as u32, BYTES_PER_CHUNK))
+        );
+
+        let chunk = BYTES_PER_CHUNK as usize;
+        assert_eq!(
+            storage.get_chunks(&song, 1, 2).await?,
+            data[chunk..3 * chunk]
+        );
+        let last = data.len() / chunk;
+        assert_eq!(
+            storage.get_chunks(&song, last as u32, 5).await?,
+            data[last * chunk..]
+        );
+
+        match storage.corrupt_chunks(&song).await {
+            Ok(corrupt_chunks) => assert!(corrupt_chunks.is_empty()),
+            Err(e) => assert!(e.to_string().contains("no hashes")),
+        }
+
+        assert!(storage.delete(&song).await?);
+        assert!(!storage.delete(&song).await?);
+        assert_eq!(storage.list().await?, vec![]);
+
+        // Only one of concurrent puts of the same song succeeds.
+        let (first, second) = tokio::join!(storage.put(&song, &data), storage.put(&song, &data));
+        assert!(first.is_ok() != second.is_ok());
+        assert_eq!(storage.list().await?, vec![song]);
+        assert_eq!(storage.get_chunks(&song, 0, 1).await?, data[..chunk]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sqlite_storage() -> eyre::Result<()> {
+        let database = Database::initialize_in_memory().await?;
+        storage_behaves_like_sqlite(&*StorageSettings::Sqlite.open(database)?).await
+    }
+
+    #[tokio::test]
+    async fn filesystem_storage() -> eyre::Result<()> {
+        let directory = std::env::temp_dir().join(format!("tangle-tunes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let database = Database::initialize_in_memory().await?;
+        let settings = StorageSettings::Filesystem {
+            directory: directory.clone(),
+        };
+        let result = storage_behaves_like_sqlite(&*settings.open(database)?).await;
+        // No temporary files are left behind
+        let files = std::fs::read_dir(&directory)?.count();
+        std::fs::remove_dir_all(&directory)?;
+        assert_eq!(files, 1);
+        result
+    }
+}
@@ -0,0 +1,52 @@
+use super::SongStorage;
+use crate::library::{database::Database, util::SongId};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+/// Stores songs in the database, with a row and hash per chunk in the `song_chunks` table.
+#[derive(Debug, Clone, Copy)]
+pub struct SqliteStorage {
+    database: Database,
+}
+
+impl SqliteStorage {
+    pub fn new(database: Database) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl SongStorage for SqliteStorage {
+    async fn put(&self, id: &SongId, data: &[u8]) -> eyre::Result<()> {
+        self.database.add_song(id, data).await
+    }
+
+    async fn get_chunks(
+        &self,
+        id: &SongId,
+        chunk_start: u32,
+        chunks: u32,
+    ) -> eyre::Result<Vec<u8>> {
+        self.database.get_chunks(id, chunk_start, chunks).await
+    }
+
+    async fn delete(&self, id: &SongId) -> eyre::Result<bool> {
+        self.database.remove_song(id).await
+    }
+
+    async fn list(&self) -> eyre::Result<Vec<SongId>> {
+        self.database.get_all_downloaded_song_ids().await
+    }
+
+    async fn list_since(&self, since: &DateTime<Utc>) -> eyre::Result<Vec<SongId>> {
+        self.database.get_new_songs(since).await
+    }
+
+    async fn size(&self, id: &SongId) -> eyre::Result<Option<u32>> {
+        self.database.get_song_len(id).await
+    }
+
+    async fn corrupt_chunks(&self, id: &SongId) -> eyre::Result<Vec<u32>> {
+        self.database.get_corrupt_chunks(id).await
+    }
+}
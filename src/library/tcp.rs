@@ -1,32 +1,396 @@
-use bytes::{Bytes, BytesMut};
+use crate::BYTES_PER_CHUNK;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use ethers::types::Signature;
+use std::{fmt::Display, net::SocketAddr, str::FromStr};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Instant,
+};
 use tokio_util::codec::{Decoder, Encoder};
 
+/// The version of the protocol, which is exchanged in the handshake.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The version of this software, which is exchanged in the handshake.
+pub const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A start-chunk-id that marks a control-frame instead of chunks.
+const CONTROL_FRAME_MARKER: u32 = u32::MAX;
+
+/// The maximum amount of chunks the distributor sends in a single frame.
+pub const MAX_CHUNKS_PER_FRAME: u32 = 10;
+
+/// The default maximum body-length of a frame sent by the listener, which fits one rlp-encoded
+/// get-chunks transaction or control-frame.
+pub const DEFAULT_MAX_REQUEST_LEN: u32 = 1024;
+
+/// The default maximum body-length of a frame sent by the distributor.
+pub const DEFAULT_MAX_RESPONSE_LEN: u32 = MAX_CHUNKS_PER_FRAME * BYTES_PER_CHUNK;
+
+/// A first byte that marks a control-frame instead of a get-chunks transaction. Rlp-encoded
+/// transactions never start with this byte, since it would prefix a list of over 2^56 bytes.
+const REQUEST_CONTROL_FRAME_MARKER: u8 = 0xff;
+
+/// The prefix of a server-address that accepts TLS-connections.
+const TLS_PREFIX: &str = "tls://";
+
+//------------------------------------------------------------------------------------------------
+//  ServerAddress
+//------------------------------------------------------------------------------------------------
+
+/// The address of a distributor as it is registered on the smart-contract, which is either
+/// `ip:port` for plaintext or `tls://ip:port` for TLS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerAddress {
+    pub socket_address: SocketAddr,
+    pub tls: bool,
+}
+
+impl FromStr for ServerAddress {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, tls) = match s.strip_prefix(TLS_PREFIX) {
+            Some(address) => (address, true),
+            None => (s, false),
+        };
+        Ok(Self {
+            socket_address: address.parse()?,
+            tls,
+        })
+    }
+}
+
+impl Display for ServerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.tls {
+            write!(f, "{TLS_PREFIX}")?;
+        }
+        write!(f, "{}", self.socket_address)
+    }
+}
+
+/// A connection between a listener and a distributor, either plaintext or over TLS.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+//------------------------------------------------------------------------------------------------
+//  ControlFrame
+//------------------------------------------------------------------------------------------------
+
+/// The reason a distributor refuses a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The distributor does not hold the requested song.
+    SongNotHeld,
+    /// The requested chunks run past the end of the song.
+    OutOfRange,
+    /// The get-chunks transaction is invalid.
+    BadTransaction,
+    /// The listener cannot pay for the requested chunks.
+    InsufficientFunds,
+    /// The listener has debt that will not be paid off.
+    DebtExceeded,
+    /// The listener sends requests too fast.
+    RateLimited,
+    /// Something went wrong at the distributor.
+    Internal,
+    /// The distributor does not speak the protocol-version of the listener.
+    UnsupportedVersion,
+    /// The distributor has too many open connections, in total or from this listener.
+    Busy,
+    /// An error-code from a newer version of the protocol.
+    Unknown(u16),
+}
+
+impl From<u16> for ErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::SongNotHeld,
+            2 => Self::OutOfRange,
+            3 => Self::BadTransaction,
+            4 => Self::InsufficientFunds,
+            5 => Self::DebtExceeded,
+            6 => Self::RateLimited,
+            7 => Self::Internal,
+            8 => Self::UnsupportedVersion,
+            9 => Self::Busy,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(value: ErrorCode) -> Self {
+        match value {
+            ErrorCode::SongNotHeld => 1,
+            ErrorCode::OutOfRange => 2,
+            ErrorCode::BadTransaction => 3,
+            ErrorCode::InsufficientFunds => 4,
+            ErrorCode::DebtExceeded => 5,
+            ErrorCode::RateLimited => 6,
+            ErrorCode::Internal => 7,
+            ErrorCode::UnsupportedVersion => 8,
+            ErrorCode::Busy => 9,
+            ErrorCode::Unknown(other) => other,
+        }
+    }
+}
+
+/// The optional parts of the protocol that a side of the connection supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// The features of a peer that speaks the legacy protocol, without a handshake.
+    pub const LEGACY: Self = Self(0);
+    /// Requests can be refused with an error-frame.
+    pub const ERROR_FRAMES: Self = Self(1);
+    /// The distributor answers challenges to prove that it holds the key of its address.
+    pub const PROOF_OF_ADDRESS: Self = Self(2);
+    /// The features supported by this software.
+    pub const SUPPORTED: Self = Self(Self::ERROR_FRAMES.0 | Self::PROOF_OF_ADDRESS.0);
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// A frame that controls the connection instead of carrying chunks.
+///
+/// It is encoded as `[version: u8][kind: u8][payload]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlFrame {
+    /// The request was refused, encoded as `[code: u16][message: utf8]`.
+    Error { code: ErrorCode, message: String },
+    /// The first frame sent by the listener, encoded as `[features: u32][software: utf8]`.
+    Hello {
+        version: u8,
+        software: String,
+        features: Features,
+    },
+    /// The reply of the distributor to a hello-frame with a supported version, encoded as
+    /// `[features: u32][software: utf8]`.
+    Welcome {
+        version: u8,
+        software: String,
+        features: Features,
+    },
+    /// A nonce sent by the listener that the distributor must sign, encoded as `[nonce: 32 bytes]`.
+    Challenge { nonce: [u8; 32] },
+    /// The signature of the distributor over a challenge, encoded as `[signature: 65 bytes]`.
+    Proof { signature: Signature },
+}
+
+impl ControlFrame {
+    const ERROR: u8 = 1;
+    const HELLO: u8 = 2;
+    const WELCOME: u8 = 3;
+    const CHALLENGE: u8 = 4;
+    const PROOF: u8 = 5;
+
+    pub fn error(code: ErrorCode, message: impl ToString) -> Self {
+        Self::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    /// A hello-frame with our version and features.
+    pub fn hello() -> Self {
+        Self::Hello {
+            version: PROTOCOL_VERSION,
+            software: SOFTWARE_VERSION.to_string(),
+            features: Features::SUPPORTED,
+        }
+    }
+
+    /// A welcome-frame with our version and features.
+    pub fn welcome() -> Self {
+        Self::Welcome {
+            version: PROTOCOL_VERSION,
+            software: SOFTWARE_VERSION.to_string(),
+            features: Features::SUPPORTED,
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            ControlFrame::Error { message, .. } => 2 + 2 + message.len(),
+            ControlFrame::Hello { software, .. } | ControlFrame::Welcome { software, .. } => {
+                2 + 4 + software.len()
+            }
+            ControlFrame::Challenge { .. } => 2 + 32,
+            ControlFrame::Proof { .. } => 2 + 65,
+        }
+    }
+
+    fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            ControlFrame::Error { code, message } => {
+                dst.put_u8(PROTOCOL_VERSION);
+                dst.put_u8(Self::ERROR);
+                dst.put_u16_le((*code).into());
+                dst.extend_from_slice(message.as_bytes());
+            }
+            ControlFrame::Hello {
+                version,
+                software,
+                features,
+            } => {
+                dst.put_u8(*version);
+                dst.put_u8(Self::HELLO);
+                dst.put_u32_le(features.0);
+                dst.extend_from_slice(software.as_bytes());
+            }
+            ControlFrame::Welcome {
+                version,
+                software,
+                features,
+            } => {
+                dst.put_u8(*version);
+                dst.put_u8(Self::WELCOME);
+                dst.put_u32_le(features.0);
+                dst.extend_from_slice(software.as_bytes());
+            }
+            ControlFrame::Challenge { nonce } => {
+                dst.put_u8(PROTOCOL_VERSION);
+                dst.put_u8(Self::CHALLENGE);
+                dst.extend_from_slice(nonce);
+            }
+            ControlFrame::Proof { signature } => {
+                dst.put_u8(PROTOCOL_VERSION);
+                dst.put_u8(Self::PROOF);
+                dst.extend_from_slice(&signature.to_vec());
+            }
+        }
+    }
+
+    /// Decode a control-frame. The layout of these frames is the same for every version, so that
+    /// peers with a different version can still be refused cleanly.
+    fn decode(mut body: BytesMut) -> eyre::Result<Self> {
+        if body.len() < 2 {
+            bail!("Control-frame of {} bytes is too short", body.len());
+        }
+        let (version, kind) = (body.get_u8(), body.get_u8());
+
+        match kind {
+            Self::ERROR => {
+                if body.len() < 2 {
+                    bail!("Error-frame without an error-code");
+                }
+                let code = body.get_u16_le().into();
+                let message = String::from_utf8_lossy(&body).into_owned();
+                Ok(Self::Error { code, message })
+            }
+            Self::HELLO | Self::WELCOME => {
+                if body.len() < 4 {
+                    bail!("Handshake-frame without features");
+                }
+                let features = Features(body.get_u32_le());
+                let software = String::from_utf8_lossy(&body).into_owned();
+                Ok(match kind {
+                    Self::HELLO => Self::Hello {
+                        version,
+                        software,
+                        features,
+                    },
+                    _ => Self::Welcome {
+                        version,
+                        software,
+                        features,
+                    },
+                })
+            }
+            Self::CHALLENGE => {
+                let nonce = body[..]
+                    .try_into()
+                    .map_err(|_| eyre!("Challenge-frame of {} bytes", body.len()))?;
+                Ok(Self::Challenge { nonce })
+            }
+            Self::PROOF => Ok(Self::Proof {
+                signature: Signature::try_from(&body[..])?,
+            }),
+            other => bail!("Control-frame has unknown kind {other}"),
+        }
+    }
+}
+
+/// The reason a frame could not be decoded.
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    #[error("Frame of {len} bytes exceeds the maximum of {max} bytes")]
+    TooLarge { len: u32, max: u32 },
+    #[error("Invalid control-frame: {0:#}")]
+    InvalidControlFrame(eyre::Report),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
 //------------------------------------------------------------------------------------------------
 //  RequestChunks
 //------------------------------------------------------------------------------------------------
 
 pub struct RequestChunksDecoder {
     body_len: Option<u32>,
+    max_len: u32,
+    partial_since: Option<Instant>,
+}
+
+impl Default for RequestChunksDecoder {
+    fn default() -> Self {
+        Self::with_max_len(DEFAULT_MAX_REQUEST_LEN)
+    }
 }
 
 impl RequestChunksDecoder {
-    pub fn new() -> Self {
-        Self { body_len: None }
+    /// Create a decoder that fails on frames with a body longer than `max_len` bytes.
+    pub fn with_max_len(max_len: u32) -> Self {
+        Self {
+            body_len: None,
+            max_len,
+            partial_since: None,
+        }
     }
+
+    /// When the first bytes of the frame that is being received arrived, or `None` if no frame is
+    /// being received.
+    pub fn partial_frame_since(&self) -> Option<Instant> {
+        self.partial_since
+    }
+}
+
+/// A frame sent by the listener.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RequestChunksFrame {
+    /// A signed rlp-encoded get-chunks transaction.
+    GetChunks(BytesMut),
+    Control(ControlFrame),
 }
 
 impl Decoder for RequestChunksDecoder {
-    type Item = BytesMut;
+    type Item = RequestChunksFrame;
 
-    type Error = eyre::Error;
+    type Error = FrameError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.partial_since.is_none() && !src.is_empty() {
+            self.partial_since = Some(Instant::now());
+        }
+
         if self.body_len.is_none() {
             if src.len() < 4 {
                 return Ok(None);
             }
-            let bytes = src.split_to(4).as_ref().try_into()?;
-            self.body_len = Some(u32::from_le_bytes(bytes));
+            let body_len = src.get_u32_le();
+            if body_len > self.max_len {
+                return Err(FrameError::TooLarge {
+                    len: body_len,
+                    max: self.max_len,
+                });
+            }
+            self.body_len = Some(body_len);
         }
 
         let body_len = self.body_len.unwrap() as usize;
@@ -34,9 +398,17 @@ impl Decoder for RequestChunksDecoder {
             return Ok(None);
         }
 
-        let rlp = src.split_to(body_len);
+        let mut body = src.split_to(body_len);
         let _body_len = self.body_len.take().unwrap();
-        Ok(Some(rlp))
+        self.partial_since = None;
+
+        if body.first() == Some(&REQUEST_CONTROL_FRAME_MARKER) {
+            body.advance(1);
+            let frame = ControlFrame::decode(body).map_err(FrameError::InvalidControlFrame)?;
+            Ok(Some(RequestChunksFrame::Control(frame)))
+        } else {
+            Ok(Some(RequestChunksFrame::GetChunks(body)))
+        }
     }
 }
 
@@ -56,6 +428,20 @@ impl Encoder<&Bytes> for RequestChunksEncoder {
     }
 }
 
+impl Encoder<&ControlFrame> for RequestChunksEncoder {
+    type Error = eyre::Error;
+
+    fn encode(&mut self, item: &ControlFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let body_len: [u8; 4] = (1 + item.encoded_len() as u32).to_le_bytes();
+
+        dst.reserve(5 + item.encoded_len());
+        dst.extend_from_slice(&body_len);
+        dst.put_u8(REQUEST_CONTROL_FRAME_MARKER);
+        item.encode(dst);
+        Ok(())
+    }
+}
+
 //------------------------------------------------------------------------------------------------
 //  SendChunks
 //------------------------------------------------------------------------------------------------
@@ -63,39 +449,58 @@ impl Encoder<&Bytes> for RequestChunksEncoder {
 pub struct SendChunksDecoder {
     start_chunk_id: Option<u32>,
     body_len: Option<u32>,
+    max_len: u32,
+}
+
+impl Default for SendChunksDecoder {
+    fn default() -> Self {
+        Self::with_max_len(DEFAULT_MAX_RESPONSE_LEN)
+    }
 }
 
 impl SendChunksDecoder {
-    pub fn new() -> Self {
+    /// Create a decoder that fails on frames with a body longer than `max_len` bytes.
+    pub fn with_max_len(max_len: u32) -> Self {
         Self {
             body_len: None,
             start_chunk_id: None,
+            max_len,
         }
     }
 }
 
-impl Decoder for SendChunksDecoder {
+/// A frame sent by the distributor.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendChunksFrame {
     /// The start-chunk-id and the chunk-bytes
-    type Item = (u32, BytesMut);
-    type Error = eyre::Error;
+    Chunks(u32, BytesMut),
+    Control(ControlFrame),
+}
+
+impl Decoder for SendChunksDecoder {
+    type Item = SendChunksFrame;
+    type Error = FrameError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         if self.start_chunk_id.is_none() {
             if src.len() < 4 {
                 return Ok(None);
             }
-            self.start_chunk_id = Some(u32::from_le_bytes(
-                src.split_to(4).as_ref().try_into().unwrap(),
-            ));
+            self.start_chunk_id = Some(src.get_u32_le());
         };
 
         if self.body_len.is_none() {
             if src.len() < 4 {
                 return Ok(None);
             }
-            self.body_len = Some(u32::from_le_bytes(
-                src.split_to(4).as_ref().try_into().unwrap(),
-            ));
+            let body_len = src.get_u32_le();
+            if body_len > self.max_len {
+                return Err(FrameError::TooLarge {
+                    len: body_len,
+                    max: self.max_len,
+                });
+            }
+            self.body_len = Some(body_len);
         }
 
         let body_len = self.body_len.unwrap() as usize;
@@ -103,10 +508,16 @@ impl Decoder for SendChunksDecoder {
             return Ok(None);
         }
 
-        let chunks = src.split_to(body_len);
+        let body = src.split_to(body_len);
         let start_chunk_id = self.start_chunk_id.take().unwrap();
         let _body_len = self.body_len.take().unwrap();
-        Ok(Some((start_chunk_id, chunks)))
+
+        if start_chunk_id == CONTROL_FRAME_MARKER {
+            let frame = ControlFrame::decode(body).map_err(FrameError::InvalidControlFrame)?;
+            Ok(Some(SendChunksFrame::Control(frame)))
+        } else {
+            Ok(Some(SendChunksFrame::Chunks(start_chunk_id, body)))
+        }
     }
 }
 
@@ -126,3 +537,328 @@ impl Encoder<(u32, &Bytes)> for SendChunksEncoder {
         Ok(())
     }
 }
+
+impl Encoder<&ControlFrame> for SendChunksEncoder {
+    type Error = eyre::Error;
+
+    fn encode(&mut self, item: &ControlFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let body_len: [u8; 4] = (item.encoded_len() as u32).to_le_bytes();
+
+        dst.reserve(8 + item.encoded_len());
+        dst.extend_from_slice(&CONTROL_FRAME_MARKER.to_le_bytes());
+        dst.extend_from_slice(&body_len);
+        item.encode(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn server_address_roundtrip() {
+        for address in ["127.0.0.1:3000", "tls://127.0.0.1:3000", "tls://[::1]:3000"] {
+            assert_eq!(
+                address.parse::<ServerAddress>().unwrap().to_string(),
+                address
+            );
+        }
+        assert!("tls://127.0.0.1:3000".parse::<ServerAddress>().unwrap().tls);
+        assert!("https://127.0.0.1:3000".parse::<ServerAddress>().is_err());
+    }
+
+    #[test]
+    fn request_chunks_roundtrip() {
+        let mut buf = BytesMut::new();
+        let request = Bytes::from_static(&[1, 2, 3, 4, 5]);
+        RequestChunksEncoder.encode(&request, &mut buf).unwrap();
+
+        let mut decoder = RequestChunksDecoder::default();
+        let mut partial = buf.split_to(6);
+        assert_eq!(decoder.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(
+            decoder.decode(&mut partial).unwrap(),
+            Some(RequestChunksFrame::GetChunks(BytesMut::from(&request[..])))
+        );
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn handshake_roundtrip() {
+        let mut buf = BytesMut::new();
+        let request = Bytes::from_static(&[0xf8, 2, 3]);
+        RequestChunksEncoder
+            .encode(&ControlFrame::hello(), &mut buf)
+            .unwrap();
+        RequestChunksEncoder.encode(&request, &mut buf).unwrap();
+
+        let mut decoder = RequestChunksDecoder::default();
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(RequestChunksFrame::Control(ControlFrame::hello()))
+        );
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(RequestChunksFrame::GetChunks(BytesMut::from(&request[..])))
+        );
+
+        SendChunksEncoder
+            .encode(&ControlFrame::welcome(), &mut buf)
+            .unwrap();
+        assert_eq!(
+            SendChunksDecoder::default().decode(&mut buf).unwrap(),
+            Some(SendChunksFrame::Control(ControlFrame::welcome()))
+        );
+    }
+
+    #[test]
+    fn challenge_roundtrip() {
+        let mut buf = BytesMut::new();
+        let challenge = ControlFrame::Challenge { nonce: [3; 32] };
+        RequestChunksEncoder.encode(&challenge, &mut buf).unwrap();
+        assert_eq!(
+            RequestChunksDecoder::default().decode(&mut buf).unwrap(),
+            Some(RequestChunksFrame::Control(challenge))
+        );
+
+        let proof = ControlFrame::Proof {
+            signature: Signature {
+                r: 1.into(),
+                s: 2.into(),
+                v: 27,
+            },
+        };
+        SendChunksEncoder.encode(&proof, &mut buf).unwrap();
+        assert_eq!(
+            SendChunksDecoder::default().decode(&mut buf).unwrap(),
+            Some(SendChunksFrame::Control(proof))
+        );
+    }
+
+    #[test]
+    fn hello_with_other_version_decodes() {
+        let hello = ControlFrame::Hello {
+            version: PROTOCOL_VERSION + 1,
+            software: "9.9.9".to_string(),
+            features: Features(u32::MAX),
+        };
+        let mut buf = BytesMut::new();
+        RequestChunksEncoder.encode(&hello, &mut buf).unwrap();
+        assert_eq!(
+            RequestChunksDecoder::default().decode(&mut buf).unwrap(),
+            Some(RequestChunksFrame::Control(hello))
+        );
+        assert!(Features(u32::MAX).contains(Features::SUPPORTED));
+        assert!(!Features::LEGACY.contains(Features::ERROR_FRAMES));
+    }
+
+    #[test]
+    fn send_chunks_roundtrip() {
+        let mut buf = BytesMut::new();
+        let chunks = Bytes::from_static(&[9; 100]);
+        let error = ControlFrame::error(ErrorCode::SongNotHeld, "Song not held");
+        SendChunksEncoder.encode((7, &chunks), &mut buf).unwrap();
+        SendChunksEncoder.encode(&error, &mut buf).unwrap();
+
+        let mut decoder = SendChunksDecoder::default();
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(SendChunksFrame::Chunks(7, BytesMut::from(&chunks[..])))
+        );
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(SendChunksFrame::Control(error))
+        );
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn error_codes_roundtrip() {
+        for code in 0..10_u16 {
+            assert_eq!(u16::from(ErrorCode::from(code)), code);
+        }
+        assert_eq!(ErrorCode::from(1000), ErrorCode::Unknown(1000));
+    }
+
+    #[test]
+    fn partial_frames_are_tracked() {
+        let mut buf = BytesMut::new();
+        RequestChunksEncoder
+            .encode(&Bytes::from_static(&[1, 2, 3]), &mut buf)
+            .unwrap();
+        let mut partial = buf.split_to(5);
+
+        let mut decoder = RequestChunksDecoder::default();
+        assert_eq!(decoder.decode(&mut BytesMut::new()).unwrap(), None);
+        assert_eq!(decoder.partial_frame_since(), None);
+        assert_eq!(decoder.decode(&mut partial).unwrap(), None);
+        assert!(decoder.partial_frame_since().is_some());
+        partial.unsplit(buf);
+        assert!(decoder.decode(&mut partial).unwrap().is_some());
+        assert_eq!(decoder.partial_frame_since(), None);
+    }
+
+    #[test]
+    fn frames_over_max_len_fail() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            RequestChunksDecoder::default().decode(&mut buf),
+            Err(FrameError::TooLarge { len: u32::MAX, .. })
+        ));
+
+        let mut buf = BytesMut::new();
+        let chunks = Bytes::from_static(&[1; 11]);
+        SendChunksEncoder.encode((0, &chunks), &mut buf).unwrap();
+        assert!(matches!(
+            SendChunksDecoder::with_max_len(10).decode(&mut buf),
+            Err(FrameError::TooLarge { len: 11, max: 10 })
+        ));
+    }
+
+    #[test]
+    fn control_frame_with_unknown_kind_fails() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&CONTROL_FRAME_MARKER.to_le_bytes());
+        buf.extend_from_slice(&4_u32.to_le_bytes());
+        buf.extend_from_slice(&[PROTOCOL_VERSION, 200, 1, 0]);
+        assert!(SendChunksDecoder::default().decode(&mut buf).is_err());
+    }
+
+    /// Decode all frames from the bytes, fed to the decoder in the given pieces.
+    fn decode_in_pieces<D: Decoder>(
+        decoder: &mut D,
+        bytes: &[u8],
+        piece_len: usize,
+    ) -> Result<Vec<D::Item>, D::Error> {
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        for piece in bytes.chunks(piece_len) {
+            buf.extend_from_slice(piece);
+            while let Some(frame) = decoder.decode(&mut buf)? {
+                frames.push(frame);
+            }
+        }
+        Ok(frames)
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn request_chunks_roundtrip_in_pieces(
+            requests in proptest::collection::vec(
+                proptest::collection::vec(0..0xff_u8, 1..DEFAULT_MAX_REQUEST_LEN as usize), 1..5
+            ),
+            piece_len in 1..2000_usize,
+        ) {
+            let mut buf = BytesMut::new();
+            for request in &requests {
+                RequestChunksEncoder.encode(&Bytes::from(request.clone()), &mut buf).unwrap();
+            }
+
+            let mut decoder = RequestChunksDecoder::default();
+            let frames = decode_in_pieces(&mut decoder, &buf, piece_len).unwrap();
+            let expected = requests
+                .iter()
+                .map(|request| RequestChunksFrame::GetChunks(BytesMut::from(&request[..])))
+                .collect::<Vec<_>>();
+            proptest::prop_assert_eq!(frames, expected);
+        }
+
+        #[test]
+        fn send_chunks_roundtrip_in_pieces(
+            responses in proptest::collection::vec(
+                (
+                    0..CONTROL_FRAME_MARKER,
+                    proptest::collection::vec(proptest::num::u8::ANY, 0..1000),
+                ),
+                1..5
+            ),
+            piece_len in 1..2000_usize,
+        ) {
+            let mut buf = BytesMut::new();
+            for (start_chunk_id, chunks) in &responses {
+                let chunks = Bytes::from(chunks.clone());
+                SendChunksEncoder.encode((*start_chunk_id, &chunks), &mut buf).unwrap();
+            }
+
+            let frames = decode_in_pieces(&mut SendChunksDecoder::default(), &buf, piece_len).unwrap();
+            let expected = responses
+                .iter()
+                .map(|(id, chunks)| SendChunksFrame::Chunks(*id, BytesMut::from(&chunks[..])))
+                .collect::<Vec<_>>();
+            proptest::prop_assert_eq!(frames, expected);
+        }
+
+        #[test]
+        fn request_decoder_handles_garbage(
+            bytes in proptest::collection::vec(proptest::num::u8::ANY, 0..4000),
+            piece_len in 1..100_usize,
+            max_len in 0..2000_u32,
+        ) {
+            let mut decoder = RequestChunksDecoder::with_max_len(max_len);
+            if let Ok(frames) = decode_in_pieces(&mut decoder, &bytes, piece_len) {
+                for frame in frames {
+                    if let RequestChunksFrame::GetChunks(body) = frame {
+                        proptest::prop_assert!(body.len() <= max_len as usize);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn send_decoder_handles_garbage(
+            bytes in proptest::collection::vec(proptest::num::u8::ANY, 0..4000),
+            piece_len in 1..100_usize,
+            max_len in 0..2000_u32,
+        ) {
+            let mut decoder = SendChunksDecoder::with_max_len(max_len);
+            if let Ok(frames) = decode_in_pieces(&mut decoder, &bytes, piece_len) {
+                for frame in frames {
+                    if let SendChunksFrame::Chunks(_, body) = frame {
+                        proptest::prop_assert!(body.len() <= max_len as usize);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn requests_over_max_len_fail(
+            max_len in 0..2000_u32,
+            extra in 1..1000_u32,
+            piece_len in 1..100_usize,
+        ) {
+            let mut buf = BytesMut::new();
+            let request = Bytes::from(vec![1; (max_len + extra) as usize]);
+            RequestChunksEncoder.encode(&request, &mut buf).unwrap();
+
+            let mut decoder = RequestChunksDecoder::with_max_len(max_len);
+            let result = decode_in_pieces(&mut decoder, &buf, piece_len);
+            let len = max_len + extra;
+            proptest::prop_assert!(
+                matches!(result, Err(FrameError::TooLarge { len: l, max: m }) if l == len && m == max_len),
+                "{result:?}"
+            );
+        }
+
+        #[test]
+        fn responses_over_max_len_fail(
+            max_len in 0..2000_u32,
+            extra in 1..1000_u32,
+            piece_len in 1..100_usize,
+        ) {
+            let mut buf = BytesMut::new();
+            let chunks = Bytes::from(vec![1; (max_len + extra) as usize]);
+            SendChunksEncoder.encode((0, &chunks), &mut buf).unwrap();
+
+            let mut decoder = SendChunksDecoder::with_max_len(max_len);
+            let result = decode_in_pieces(&mut decoder, &buf, piece_len);
+            let len = max_len + extra;
+            proptest::prop_assert!(
+                matches!(result, Err(FrameError::TooLarge { len: l, max: m }) if l == len && m == max_len),
+                "{result:?}"
+            );
+        }
+    }
+}
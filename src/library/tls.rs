@@ -0,0 +1,178 @@
+use ethers::utils::keccak256;
+use eyre::Context;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, PrivateKey, ServerConfig, ServerName,
+};
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+/// The paths of the certificate-chain and private key used by the distributor, both PEM-encoded.
+#[derive(Debug, Clone)]
+pub struct TlsPaths {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Accepts TLS-connections with the certificate of the distributor.
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+    cert_hash: [u8; 32],
+}
+
+impl TlsServer {
+    /// Load the certificate-chain and private key from the given paths.
+    pub fn load(paths: &TlsPaths) -> eyre::Result<Self> {
+        let certs = read_pem(&paths.cert_path)?
+            .into_iter()
+            .filter_map(|item| match item {
+                rustls_pemfile::Item::X509Certificate(cert) => Some(Certificate(cert)),
+                _ => None,
+            })
+            .collect();
+        let key = read_pem(&paths.key_path)?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or_else(|| eyre!("No private key found in {:?}", paths.key_path))?;
+
+        Self::new(certs, key)
+    }
+
+    /// Create the server from a DER-encoded certificate-chain and private key.
+    pub fn new(certs: Vec<Certificate>, key: PrivateKey) -> eyre::Result<Self> {
+        let Some(cert) = certs.first() else {
+            bail!("No certificate given");
+        };
+        let cert_hash = cert_hash(cert);
+
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            cert_hash,
+        })
+    }
+
+    /// The hash of our certificate, which binds the proof of address to this connection.
+    pub fn cert_hash(&self) -> [u8; 32] {
+        self.cert_hash
+    }
+
+    /// Perform the TLS-handshake on an accepted connection.
+    pub async fn accept(&self, stream: TcpStream) -> eyre::Result<server::TlsStream<TcpStream>> {
+        Ok(self.acceptor.accept(stream).await?)
+    }
+}
+
+/// Connect to a distributor over TLS.
+///
+/// Distributors use self-signed certificates, so any certificate is accepted. Instead, the hash of
+/// the certificate is returned, which the distributor must include in its proof of address. A
+/// man-in-the-middle can then not forward the proof, since its certificate has a different hash.
+pub async fn connect(
+    socket_address: SocketAddr,
+) -> eyre::Result<(client::TlsStream<TcpStream>, [u8; 32])> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+        .with_no_client_auth();
+
+    let stream = TcpStream::connect(socket_address).await?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::IpAddress(socket_address.ip()), stream)
+        .await?;
+
+    let cert = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .ok_or_else(|| eyre!("Distributor did not send a certificate"))?;
+    let cert_hash = cert_hash(cert);
+
+    Ok((stream, cert_hash))
+}
+
+fn cert_hash(cert: &Certificate) -> [u8; 32] {
+    keccak256(&cert.0)
+}
+
+fn read_pem(path: &Path) -> eyre::Result<Vec<rustls_pemfile::Item>> {
+    let file = File::open(path).wrap_err(format!("Could not open {path:?}"))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .wrap_err(format!("Could not parse PEM-file at {path:?}"))
+}
+
+/// Accepts any certificate, the identity of the distributor is checked with its proof of address.
+struct AnyCertificate;
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn self_signed() -> TlsServer {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        TlsServer::new(
+            vec![Certificate(cert.serialize_der().unwrap())],
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tls_over_loopback() -> eyre::Result<()> {
+        let server = self_signed();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = server.accept(stream).await?;
+            stream.write_all(b"chunks").await?;
+            stream.shutdown().await?;
+            Ok::<_, eyre::Report>(server.cert_hash())
+        });
+
+        let (mut stream, cert_hash) = connect(address).await?;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await?;
+
+        assert_eq!(received, b"chunks");
+        assert_eq!(handle.await??, cert_hash);
+        Ok(())
+    }
+}
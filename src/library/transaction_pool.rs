@@ -1,24 +1,63 @@
 use std::{collections::VecDeque, time::Duration};
 
-use ethers::types::{Bytes, TransactionReceipt};
-use ethers_providers::{Http, PendingTransaction, StreamExt};
-use eyre::Context;
+use ethers::types::{Bytes, TransactionReceipt, TxHash};
+use ethers_providers::{PendingTransaction, RpcError, StreamExt};
 use futures::{future::BoxFuture, stream::FuturesUnordered};
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 
-use super::client::TangleTunesClient;
+use super::{
+    client::{MeteredHttp, TangleTunesClient},
+    metrics::METRICS,
+};
+
+/// How long a transaction may take to be included in a block after it has been sent.
+pub const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Why a transaction of the pool failed.
+#[derive(Debug, thiserror::Error)]
+pub enum TxFailure {
+    /// The node could not be reached after all attempts, or it rejected the transaction.
+    #[error("Sending the transaction failed: {0:#}")]
+    Send(eyre::Report),
+    /// The transaction was included in a block, but reverted.
+    #[error("Transaction {0:?} reverted")]
+    Reverted(TxHash),
+    /// The transaction was dropped from the mempool, also after sending it again.
+    #[error("Transaction {0:?} was dropped from the mempool")]
+    Dropped(TxHash),
+    /// The transaction was not included in a block within the confirmation-timeout.
+    #[error("Transaction {0:?} was not confirmed within {CONFIRMATION_TIMEOUT:?}")]
+    Timeout(TxHash),
+}
+
+impl TxFailure {
+    /// A short name of the kind of failure, as stored in the database.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TxFailure::Send(_) => "send",
+            TxFailure::Reverted(_) => "reverted",
+            TxFailure::Dropped(_) => "dropped",
+            TxFailure::Timeout(_) => "timeout",
+        }
+    }
+}
+
+/// A transaction that has been sent, together with its raw bytes.
+type Sent = (PendingTransaction<'static, MeteredHttp>, Bytes);
 
 /// Multiple pending transactions that will be executed after another. The initial sending of the
 /// transaction happens in order, while awaiting the transactions happens concurrently.
 ///
 /// By setting the timeout and attempts, the initial sending will be retried that amount of times.
+/// Transactions that are dropped from the mempool are sent once more, and errors while awaiting
+/// a transaction are retried until the confirmation-timeout.
 ///
-/// If any transaction fails for whatever reason
+/// Every transaction results in either a receipt or a [`TxFailure`], together with its value.
 #[allow(clippy::type_complexity)]
 pub struct TransactionPool<T> {
     client: &'static TangleTunesClient,
-    stage1: VecDeque<BoxFuture<'static, eyre::Result<(PendingTransaction<'static, Http>, T)>>>,
-    stage2: FuturesUnordered<BoxFuture<'static, eyre::Result<(TransactionReceipt, T)>>>,
+    stage1: VecDeque<BoxFuture<'static, (Result<Sent, TxFailure>, T)>>,
+    stage2: FuturesUnordered<BoxFuture<'static, (Result<TransactionReceipt, TxFailure>, T)>>,
     timeout: Duration,
     attempts: u32,
 }
@@ -34,7 +73,7 @@ impl<T: std::fmt::Debug + Send + 'static> TransactionPool<T> {
         }
     }
 
-    pub async fn next(&mut self) -> Option<eyre::Result<(TransactionReceipt, T)>> {
+    pub async fn next(&mut self) -> Option<(Result<TransactionReceipt, TxFailure>, T)> {
         loop {
             tokio::select! {
                 biased;
@@ -45,19 +84,26 @@ impl<T: std::fmt::Debug + Send + 'static> TransactionPool<T> {
                         None => None
                     }
                 } => {
-                    self.stage1.pop_front().unwrap();
+                    drop(self.stage1.pop_front().unwrap());
                     match res {
-                        Ok((pending_tx, val)) => {
+                        (Ok((pending_tx, tx)), val) => {
+                            let (client, attempts, timeout) =
+                                (self.client, self.attempts, self.timeout);
                             self.stage2.push(Box::pin(async move {
-                                Ok((pending_tx.await?.unwrap(), val))
+                                let res = confirm(client, pending_tx, tx, attempts, timeout).await;
+                                (res, val)
                             }));
                             continue;
                         },
-                        Err(e) => break Some(Err(e))
+                        (Err(e), val) => {
+                            METRICS.add_pending_transactions(-1);
+                            break Some((Err(e), val))
+                        }
                     }
                 }
 
                 Some(res) = self.stage2.next() => {
+                    METRICS.add_pending_transactions(-1);
                     break Some(res);
                 }
 
@@ -68,33 +114,101 @@ impl<T: std::fmt::Debug + Send + 'static> TransactionPool<T> {
         }
     }
 
+    /// Whether there are no transactions left in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.stage1.is_empty() && self.stage2.is_empty()
+    }
+
     pub fn push_raw_tx(&mut self, tx: Bytes, val: T) {
         let client = self.client;
         let attempts = self.attempts;
         let timeout = self.timeout;
 
+        METRICS.add_pending_transactions(1);
         self.stage1.push_back(Box::pin(async move {
-            let mut result: Option<eyre::Result<PendingTransaction<'static, Http>>> = None;
+            let result = send(client, tx.clone(), attempts, timeout).await;
+            (result.map(|pending_tx| (pending_tx, tx)), val)
+        }));
+    }
+}
 
-            for attempt in 0..attempts {
-                match client.send_raw_tx(tx.clone()).await {
-                    Ok(pending_tx) => {
-                        result = Some(Ok(pending_tx));
-                        break;
-                    }
-                    Err(new_err) => match result {
-                        Some(Err(err)) => result = Some(Err(err)),
-                        None => result = Some(Err(new_err)),
-                        Some(Ok(_)) => unreachable!(),
-                    },
-                }
-                sleep(timeout * 2_u32.saturating_pow(attempt)).await;
+impl<T> Drop for TransactionPool<T> {
+    fn drop(&mut self) {
+        let remaining = self.stage1.len() + self.stage2.len();
+        METRICS.add_pending_transactions(-(remaining as i64));
+    }
+}
+
+/// Send the transaction, retrying with exponential backoff if the node cannot be reached. A
+/// transaction that is rejected by the node is not sent again.
+async fn send(
+    client: &'static TangleTunesClient,
+    tx: Bytes,
+    attempts: u32,
+    timeout: Duration,
+) -> Result<PendingTransaction<'static, MeteredHttp>, TxFailure> {
+    let mut error = None;
+
+    for attempt in 0..attempts {
+        match client.send_raw_tx(tx.clone()).await {
+            Ok(pending_tx) => return Ok(pending_tx),
+            Err(e) if e.is_error_response() => {
+                let e = eyre::Report::new(e).wrap_err("Rejected by the node");
+                return Err(TxFailure::Send(e));
             }
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+        sleep(timeout * 2_u32.saturating_pow(attempt)).await;
+    }
 
-            let pending_tx = result
-                .expect("attempts > 0")
-                .wrap_err(format!("Sending the transaction failed {attempts} times"))?;
-            Ok((pending_tx, val))
-        }));
+    let e = eyre::Report::new(error.expect("attempts > 0"));
+    Err(TxFailure::Send(
+        e.wrap_err(format!("Failed {attempts} times")),
+    ))
+}
+
+/// Wait for the transaction to be included in a block.
+async fn confirm(
+    client: &'static TangleTunesClient,
+    mut pending_tx: PendingTransaction<'static, MeteredHttp>,
+    tx: Bytes,
+    attempts: u32,
+    timeout: Duration,
+) -> Result<TransactionReceipt, TxFailure> {
+    let hash = pending_tx.tx_hash();
+    let deadline = Instant::now() + CONFIRMATION_TIMEOUT;
+    let mut sent_again = false;
+    let mut poll_failures = 0;
+
+    loop {
+        let result = match tokio::time::timeout_at(deadline, pending_tx).await {
+            Ok(result) => result,
+            Err(_) => return Err(TxFailure::Timeout(hash)),
+        };
+
+        match result {
+            Ok(Some(receipt)) => {
+                return match receipt.status {
+                    Some(status) if status.is_zero() => Err(TxFailure::Reverted(hash)),
+                    _ => Ok(receipt),
+                }
+            }
+            // Dropped transactions are sent once more, if the node still accepts them.
+            Ok(None) if !sent_again => {
+                sent_again = true;
+                pending_tx = send(client, tx.clone(), attempts, timeout)
+                    .await
+                    .map_err(|_| TxFailure::Dropped(hash))?;
+            }
+            Ok(None) => return Err(TxFailure::Dropped(hash)),
+            // The node could not be reached, so we keep polling until the deadline.
+            Err(_) => {
+                sleep(timeout * 2_u32.saturating_pow(Ord::min(poll_failures, 6))).await;
+                poll_failures += 1;
+                pending_tx = client.create_pending_tx(hash, 1);
+            }
+        }
     }
 }
@@ -1,11 +1,10 @@
-use super::client::{TTCall, TangleTunesClient};
+use super::client::TTCall;
 use color_eyre::Report;
 use ethers::{
     abi::Detokenize,
     types::TransactionReceipt,
     utils::hex::{FromHex, ToHex},
 };
-use ethers_providers::{Http, PendingTransaction};
 use std::{
     error::Error,
     fmt::{Debug, Display},
@@ -13,7 +12,7 @@ use std::{
     str::FromStr,
 };
 
-#[derive(Clone, Copy, PartialEq, PartialOrd, Ord, Eq)]
+#[derive(Clone, Copy, PartialEq, PartialOrd, Ord, Eq, Hash)]
 pub struct SongId([u8; 32]);
 
 impl TryFrom<Vec<u8>> for SongId {
@@ -139,20 +138,6 @@ impl TransactionReceiptExt for TransactionReceipt {
     }
 }
 
-//------------------------------------------------------------------------------------------------
-//  PendingTx
-//------------------------------------------------------------------------------------------------
-
-pub trait PendingTransactionExt: Sized {
-    fn with_client(self, client: &TangleTunesClient) -> PendingTransaction<'_, Http>;
-}
-
-impl<'a> PendingTransactionExt for PendingTransaction<'a, Http> {
-    fn with_client(self, client: &TangleTunesClient) -> PendingTransaction<'_, Http> {
-        client.create_pending_tx(self.tx_hash(), 1)
-    }
-}
-
 //------------------------------------------------------------------------------------------------
 //  CallExt
 //------------------------------------------------------------------------------------------------
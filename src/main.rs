@@ -37,6 +37,17 @@ fn main() -> eyre::Result<()> {
                 let database = Database::initialize(&config.database_path).await?;
                 command::wallet::generate(password.to_owned(), database).await
             }
+            Command::Ctl(command) => {
+                let control_socket = config.control_socket_path(&args.config);
+                command::ctl::run(&control_socket, command.clone()).await
+            }
+            Command::Distribute { daemon: true, .. } => {
+                command::distribute::detach(
+                    &config.daemon_log_path(&args.config),
+                    &config.control_socket_path(&args.config),
+                )
+                .await
+            }
             _ => {
                 let app = ConfigFile::from_path(&args.config)?
                     .parse_to_app_builder(args.password, &args.config)?
@@ -83,6 +94,7 @@ async fn run_command(app: &'static App, command: Command) -> eyre::Result<()> {
             SongsCommand::Add { paths } => command::songs::add(paths, app).await,
             SongsCommand::Remove { ids } => command::songs::remove(ids, app).await,
             SongsCommand::List => command::songs::run_list(app).await,
+            SongsCommand::Verify => command::songs::verify(app).await,
             SongsCommand::Download { song_id, to_file } => {
                 command::songs::download(app, song_id, to_file, U256::MAX).await
             }
@@ -96,7 +108,10 @@ async fn run_command(app: &'static App, command: Command) -> eyre::Result<()> {
             AccountCommand::Delete => command::account::delete(app).await,
             AccountCommand::View => command::account::view(app).await,
         },
-        Command::Distribute { demo } => command::distribute::distribute(app, demo).await,
+        Command::Distribute { demo, .. } => command::distribute::distribute(app, demo).await,
+        Command::Ctl(_) => unreachable!(),
+        Command::Earnings { by } => command::earnings::report(app, by).await,
+        Command::Spending { by } => command::spending::report(app, by).await,
         Command::SongIndex(command) => match command {
             SongIndexCommand::Update => {
                 command::song_index::update(app).await?;